@@ -0,0 +1,80 @@
+use crate::sandbox::ShareRoot;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A file or directory to archive, under its name in the archive.
+pub struct Entry {
+    name: PathBuf,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+/// Lists what the client may see below `dir`, parents before their
+/// children. A symlinked directory that leads back to one already listed is
+/// left out, so a link loop can't make the archive endless.
+pub fn collect(root: &ShareRoot, dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::from([fs::canonicalize(dir)?]);
+    walk(root, dir, Path::new(""), &mut visited, &mut entries)?;
+    Ok(entries)
+}
+
+fn walk(
+    root: &ShareRoot,
+    dir: &Path,
+    prefix: &Path,
+    visited: &mut HashSet<PathBuf>,
+    entries: &mut Vec<Entry>,
+) -> io::Result<()> {
+    let mut names: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+    names.sort();
+    for name in names {
+        let path = dir.join(&name);
+        if !root.is_visible(&path) {
+            continue;
+        }
+        let Ok(path) = fs::canonicalize(&path) else {
+            continue;
+        };
+        let name = prefix.join(name);
+        if path.is_dir() {
+            if visited.insert(path.clone()) {
+                entries.push(Entry {
+                    name: name.clone(),
+                    path: path.clone(),
+                    is_dir: true,
+                });
+                walk(root, &path, &name, visited, entries)?;
+            }
+        } else if path.is_file() {
+            entries.push(Entry {
+                name,
+                path,
+                is_dir: false,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Writes `entries` to `writer` as a tar stream, reading each file only as
+/// its turn comes. Returns how many bytes of file content were sent.
+pub fn write(entries: &[Entry], writer: impl Write) -> io::Result<u64> {
+    let mut builder = tar::Builder::new(writer);
+    let mut bytes = 0;
+    for entry in entries {
+        if entry.is_dir {
+            builder.append_dir(&entry.name, &entry.path)?;
+        } else {
+            let mut file = File::open(&entry.path)?;
+            bytes += file.metadata()?.len();
+            builder.append_file(&entry.name, &mut file)?;
+        }
+    }
+    builder.into_inner()?.flush()?;
+    Ok(bytes)
+}
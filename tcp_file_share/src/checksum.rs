@@ -0,0 +1,49 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Hashes of served files, so a file is only read twice when it changed.
+#[derive(Default)]
+pub struct Checksums {
+    known: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl Checksums {
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> io::Result<String> {
+        let modified = metadata.modified()?;
+        if let Some((known_modified, size, hash)) = self.known.lock().unwrap().get(path)
+            && *known_modified == modified
+            && *size == metadata.len()
+        {
+            return Ok(hash.clone());
+        }
+        let hash = sha256_file(path)?;
+        self.known
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, metadata.len(), hash.clone()));
+        Ok(hash)
+    }
+}
@@ -0,0 +1,350 @@
+use crate::checksum::sha256_file;
+use crate::protocol::{Command, ErrorReply, Request, read_reply};
+use std::env;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Sends `command` with the `SHARE_TOKEN` of the environment, if any.
+fn send(
+    server: &str,
+    command: Command,
+) -> Result<(TcpStream, BufReader<TcpStream>), Box<dyn Error>> {
+    let mut stream = TcpStream::connect(server)?;
+    let request = Request {
+        token: env::var("SHARE_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+        command,
+    };
+    request.write(&mut stream)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    Ok((stream, reader))
+}
+
+/// Downloads `name` from `server` into `output`, or a file of the same name
+/// in the working directory. Bytes go to `<output>.part` first, so an
+/// interrupted download resumes where it stopped, and the file only takes
+/// its name once its SHA-256 matches the server's.
+pub fn get(server: &str, name: &str, output: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(
+            Path::new(name)
+                .file_name()
+                .ok_or_else(|| format!("Can't name a file after {}", name))?,
+        ),
+    };
+    let mut part = output.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let offset = fs::metadata(&part).map_or(0, |metadata| metadata.len());
+    let (size, hash) = match download(server, name, &part, offset) {
+        // The file shrank since the part was written, so it starts over.
+        Err(e)
+            if offset > 0
+                && e.downcast_ref::<ErrorReply>()
+                    .is_some_and(|reply| reply.code == "BAD_OFFSET") =>
+        {
+            println!(
+                "🔁 {} no longer matches the server, starting over",
+                part.display()
+            );
+            fs::remove_file(&part)?;
+            download(server, name, &part, 0)?
+        }
+        result => result?,
+    };
+
+    let received = fs::metadata(&part)?.len();
+    if received != size {
+        return Err(format!(
+            "Connection closed after {} of {} bytes, run get again to resume",
+            received, size
+        )
+        .into());
+    }
+    if sha256_file(&part)? != hash {
+        fs::remove_file(&part)?;
+        return Err("Checksum mismatch, the download was discarded".into());
+    }
+    fs::rename(&part, &output)?;
+    println!(
+        "👌 File downloaded to {} (sha256 {})",
+        output.display(),
+        hash
+    );
+    Ok(())
+}
+
+/// Appends the file from `offset` on to `part`. Returns the size and hash
+/// the server announced.
+fn download(
+    server: &str,
+    name: &str,
+    part: &Path,
+    offset: u64,
+) -> Result<(u64, String), Box<dyn Error>> {
+    let command = Command::Get {
+        name: name.to_string(),
+        offset,
+    };
+    let (_, mut reader) = send(server, command)?;
+    let (size, hash) = match &read_reply(&mut reader)?[..] {
+        [size, hash] => (size.parse::<u64>()?, hash.clone()),
+        fields => return Err(format!("Unexpected reply: OK {}", fields.join(" ")).into()),
+    };
+    if offset > 0 {
+        println!("⏩ Resuming at byte {} of {}", offset, size);
+    }
+    println!("🚀 Requested filesize: {} bytes", size);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(part)?;
+    copy_with_progress(&mut reader.take(size - offset), &mut file, offset, size)?;
+    Ok((size, hash))
+}
+
+/// Downloads the directory `dir` into `output`, or a directory of the same
+/// name in the working directory, unpacking it as it arrives. Relative
+/// paths, permissions and modification times are kept.
+pub fn get_dir(
+    server: &str,
+    dir: &str,
+    output: Option<&str>,
+    zstd: bool,
+) -> Result<(), Box<dyn Error>> {
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(dir)
+            .file_name()
+            .map_or_else(|| PathBuf::from("share"), PathBuf::from),
+    };
+    let command = Command::Archive {
+        dir: dir.to_string(),
+        zstd,
+    };
+    let (_, mut reader) = send(server, command)?;
+    let (format, count) = match &read_reply(&mut reader)?[..] {
+        [format, count] => (format.clone(), count.parse::<usize>()?),
+        fields => return Err(format!("Unexpected reply: OK {}", fields.join(" ")).into()),
+    };
+    println!("🚀 Receiving {} entries as {}", count, format);
+
+    fs::create_dir_all(&output)?;
+    let (entries, bytes) = match format.as_str() {
+        "tar" => unpack(reader, &output)?,
+        "tar+zstd" => unpack(zstd::Decoder::with_buffer(reader)?, &output)?,
+        _ => return Err(format!("Unknown archive format: {}", format).into()),
+    };
+    if entries != count {
+        return Err(format!(
+            "Connection closed after {} of {} entries, run getdir again",
+            entries, count
+        )
+        .into());
+    }
+    println!(
+        "👌 Directory downloaded to {} ({} entries, {})",
+        output.display(),
+        entries,
+        human_size(bytes)
+    );
+    Ok(())
+}
+
+/// Unpacks a tar stream into `output`. Returns the number of entries and
+/// the bytes of file content.
+fn unpack(reader: impl Read, output: &Path) -> Result<(usize, u64), Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    let (mut entries, mut bytes) = (0, 0);
+    let mut last_report = Instant::now();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // `unpack_in` refuses names that would land outside `output`.
+        if !entry.unpack_in(output)? {
+            return Err(format!("Refusing to unpack {}", entry.path()?.display()).into());
+        }
+        entries += 1;
+        bytes += entry.size();
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            print!("\r📦 {} entries, {}", entries, human_size(bytes));
+            io::stdout().flush()?;
+            last_report = Instant::now();
+        }
+    }
+    print!("\r📦 {} entries, {}", entries, human_size(bytes));
+    println!();
+    Ok((entries, bytes))
+}
+
+/// Uploads `file` as `name`, or under its own file name.
+pub fn put(server: &str, file: &str, name: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => Path::new(file)
+            .file_name()
+            .ok_or_else(|| format!("Can't name an upload after {}", file))?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let size = fs::metadata(file)?.len();
+    let sha256 = sha256_file(Path::new(file))?;
+    let command = Command::Put {
+        name: name.clone(),
+        size,
+        sha256: sha256.clone(),
+    };
+    let (mut stream, mut reader) = send(server, command)?;
+    read_reply(&mut reader)?;
+
+    println!("🚀 Uploading {} bytes", size);
+    copy_with_progress(&mut File::open(file)?, &mut stream, 0, size)?;
+    read_reply(&mut reader)?;
+    println!("👌 Uploaded {} as {} (sha256 {})", file, name, sha256);
+    Ok(())
+}
+
+pub fn list(server: &str, dir: &str) -> Result<(), Box<dyn Error>> {
+    let command = Command::List {
+        dir: dir.to_string(),
+    };
+    let (_, mut reader) = send(server, command)?;
+    let count: usize = match &read_reply(&mut reader)?[..] {
+        [count] => count.parse()?,
+        fields => return Err(format!("Unexpected reply: OK {}", fields.join(" ")).into()),
+    };
+    let mut line = String::new();
+    for _ in 0..count {
+        line.clear();
+        io::BufRead::read_line(&mut reader, &mut line)?;
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).splitn(4, ' ').collect();
+        match fields[..] {
+            ["d", _, mtime, name] => {
+                println!("{:>10}  {}  {}/", "-", format_time(mtime.parse()?), name)
+            }
+            [_, size, mtime, name] => println!(
+                "{:>10}  {}  {}",
+                human_size(size.parse()?),
+                format_time(mtime.parse()?),
+                name
+            ),
+            _ => return Err(format!("Unexpected entry: {}", line.trim()).into()),
+        }
+    }
+    println!("({} entries)", count);
+    Ok(())
+}
+
+pub fn stat(server: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    let command = Command::Stat {
+        name: name.to_string(),
+    };
+    let (_, mut reader) = send(server, command)?;
+    match &read_reply(&mut reader)?[..] {
+        [kind, size, mtime, hash] => {
+            let kind = if kind == "d" { "directory" } else { "file" };
+            println!("{}: {}", name, kind);
+            println!("  size:     {} bytes", size);
+            println!("  modified: {}", format_time(mtime.parse()?));
+            if hash != "-" {
+                println!("  sha256:   {}", hash);
+            }
+            Ok(())
+        }
+        fields => Err(format!("Unexpected reply: OK {}", fields.join(" ")).into()),
+    }
+}
+
+pub fn delete(server: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    let command = Command::Delete {
+        name: name.to_string(),
+    };
+    let (_, mut reader) = send(server, command)?;
+    read_reply(&mut reader)?;
+    println!("🗑️ Deleted {}", name);
+    Ok(())
+}
+
+/// Copies `reader` to `writer`, counting up from `done` bytes of `size`.
+fn copy_with_progress(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    done: u64,
+    size: u64,
+) -> io::Result<()> {
+    let mut done = done;
+    let mut buffer = [0; 64 * 1024];
+    let mut last_report = Instant::now();
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..n])?;
+        done += n as u64;
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            show_progress(done, size)?;
+            last_report = Instant::now();
+        }
+    }
+    writer.flush()?;
+    show_progress(done, size)?;
+    println!();
+    Ok(())
+}
+
+fn show_progress(done: u64, size: u64) -> io::Result<()> {
+    let percent = match size {
+        0 => 100,
+        size => done * 100 / size,
+    };
+    print!(
+        "\r⬇️  {:>3}% {} / {}",
+        percent,
+        human_size(done),
+        human_size(size)
+    );
+    io::stdout().flush()
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// `secs` since the epoch as a UTC date and time.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (hour, minute) = (secs % 86400 / 3600, secs % 3600 / 60);
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    )
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The most a throttled stream reads or writes at once, so a slow limit
+// still sends a steady trickle rather than bursts.
+const CHUNK: usize = 16 * 1024;
+
+/// How hard the server may be used.
+pub struct Limits {
+    global: Option<Arc<TokenBucket>>,
+    per_client: Option<u64>,
+    clients: Mutex<HashMap<IpAddr, Weak<TokenBucket>>>,
+    transfers: Option<Arc<Slots>>,
+    connections: Arc<Slots>,
+    idle_timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Reads the settings from the environment:
+    ///
+    /// * `SHARE_RATE_LIMIT`: bytes per second for all clients together.
+    /// * `SHARE_CLIENT_RATE_LIMIT`: bytes per second for each client address.
+    /// * `SHARE_MAX_TRANSFERS`: how many downloads and uploads run at once;
+    ///   the others wait for a free slot in the order they came.
+    /// * `SHARE_MAX_CONNECTIONS`: how many clients are served at once, 64 by
+    ///   default. Further connections wait in the listen backlog.
+    /// * `SHARE_IDLE_TIMEOUT`: seconds a client may stay silent, 30 by
+    ///   default, 0 for no limit.
+    ///
+    /// Rates may end in `K`, `M` or `G`. Unset means no limit.
+    pub fn from_env() -> Result<Self, String> {
+        let global = setting("SHARE_RATE_LIMIT", parse_rate)?;
+        let per_client = setting("SHARE_CLIENT_RATE_LIMIT", parse_rate)?;
+        let transfers = setting("SHARE_MAX_TRANSFERS", |value| {
+            value.parse::<usize>().ok().filter(|&max| max > 0)
+        })?;
+        let connections = setting("SHARE_MAX_CONNECTIONS", |value| {
+            value.parse::<usize>().ok().filter(|&max| max > 0)
+        })?
+        .unwrap_or(64);
+        let idle_timeout =
+            setting("SHARE_IDLE_TIMEOUT", |value| value.parse::<u64>().ok())?.unwrap_or(30);
+        Ok(Limits {
+            global: global.map(|rate| Arc::new(TokenBucket::new(rate))),
+            per_client,
+            clients: Mutex::new(HashMap::new()),
+            transfers: transfers.map(|max| Arc::new(Slots::new(max, "Transfer"))),
+            connections: Arc::new(Slots::new(connections, "Connection")),
+            idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout)),
+        })
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Wraps a client's stream in the global limit and the limit its
+    /// address shares with its other connections.
+    pub fn throttle<T>(&self, client: IpAddr, stream: T) -> Throttled<T> {
+        let mut buckets: Vec<_> = self.global.iter().cloned().collect();
+        if let Some(rate) = self.per_client {
+            let mut clients = self.clients.lock().unwrap();
+            let bucket = match clients.get(&client).and_then(Weak::upgrade) {
+                Some(bucket) => bucket,
+                None => {
+                    clients.retain(|_, bucket| bucket.strong_count() > 0);
+                    let bucket = Arc::new(TokenBucket::new(rate));
+                    clients.insert(client, Arc::downgrade(&bucket));
+                    bucket
+                }
+            };
+            buckets.push(bucket);
+        }
+        Throttled {
+            inner: stream,
+            buckets,
+        }
+    }
+
+    /// Waits for a transfer slot if their number is capped. The slot is
+    /// free again once the returned guard is dropped.
+    pub fn transfer_slot(&self) -> Option<SlotGuard> {
+        self.transfers.as_ref().map(Slots::acquire)
+    }
+
+    /// Waits until another client may be served, so the threads serving
+    /// them stay bounded.
+    pub fn connection_slot(&self) -> SlotGuard {
+        Slots::acquire(&self.connections)
+    }
+}
+
+fn setting<T>(key: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    match env::var(key) {
+        Ok(value) if !value.trim().is_empty() => parse(value.trim())
+            .map(Some)
+            .ok_or_else(|| format!("{}: invalid value {}", key, value)),
+        _ => Ok(None),
+    }
+}
+
+fn parse_rate(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1024),
+        (i, 'm' | 'M') => (&value[..i], 1024 * 1024),
+        (i, 'g' | 'G') => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|&rate| rate > 0)
+}
+
+/// Hands out `rate` bytes per second, with up to a second's worth saved up
+/// for a burst.
+struct TokenBucket {
+    rate: f64,
+    // Tokens go negative when taken ahead of time; whoever comes next waits
+    // for that debt too, so waiting clients take turns.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes `n` tokens, sleeping until the bucket has covered them.
+    fn take(&self, n: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens =
+                (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            *tokens -= n as f64;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// A stream whose reads and writes are paced by token buckets.
+pub struct Throttled<T> {
+    inner: T,
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl<T> Throttled<T> {
+    fn pace(&self, n: usize) {
+        for bucket in &self.buckets {
+            bucket.take(n);
+        }
+    }
+
+    fn chunk(&self, len: usize) -> usize {
+        if self.buckets.is_empty() {
+            len
+        } else {
+            len.min(CHUNK)
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.chunk(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        self.pace(n);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.chunk(buf.len());
+        let n = self.inner.write(&buf[..len])?;
+        self.pace(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A first come, first served cap on concurrent transfers or connections.
+struct Slots {
+    max: usize,
+    what: &'static str,
+    // Transfers running, the next ticket to hand out, and the ticket whose
+    // turn it is.
+    state: Mutex<(usize, u64, u64)>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn new(max: usize, what: &'static str) -> Self {
+        Slots {
+            max,
+            what,
+            state: Mutex::new((0, 0, 0)),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> SlotGuard {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.1;
+        state.1 += 1;
+        if state.0 >= self.max || ticket != state.2 {
+            println!(
+                "⏳ {} slots full, queued with {} ahead",
+                self.what,
+                ticket - state.2
+            );
+        }
+        while state.0 >= self.max || ticket != state.2 {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.0 += 1;
+        state.2 += 1;
+        // The next ticket may fit in a slot that is still free.
+        self.freed.notify_all();
+        SlotGuard {
+            slots: Arc::clone(self),
+        }
+    }
+}
+
+pub struct SlotGuard {
+    slots: Arc<Slots>,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.slots.state.lock().unwrap().0 -= 1;
+        self.slots.freed.notify_all();
+    }
+}
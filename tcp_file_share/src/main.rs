@@ -1,10 +1,83 @@
+mod archive;
+mod checksum;
+mod client;
+mod limits;
+mod protocol;
+mod sandbox;
+
+use checksum::{Checksums, sha256_file};
+use limits::Limits;
+use protocol::{Command, Refusal, Request};
+use sandbox::ShareRoot;
+use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::net::{TcpListener, TcpStream};
-use std::thread;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use std::{process, thread};
+
+struct Server {
+    root: ShareRoot,
+    checksums: Checksums,
+    /// Required on every request if set. Without one the share is
+    /// read-only.
+    token: Option<String>,
+    // Keeps the temporary files of concurrent uploads apart.
+    uploads: AtomicU64,
+    limits: Limits,
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] | ["serve"] => serve(),
+        ["get", server, name] => exit_on_error(client::get(server, name, None)),
+        ["get", server, name, output] => exit_on_error(client::get(server, name, Some(output))),
+        ["getdir", "--zstd", server, dir] => {
+            exit_on_error(client::get_dir(server, dir, None, true))
+        }
+        ["getdir", "--zstd", server, dir, output] => {
+            exit_on_error(client::get_dir(server, dir, Some(output), true))
+        }
+        ["getdir", server, dir] => exit_on_error(client::get_dir(server, dir, None, false)),
+        ["getdir", server, dir, output] => {
+            exit_on_error(client::get_dir(server, dir, Some(output), false))
+        }
+        ["put", server, file] => exit_on_error(client::put(server, file, None)),
+        ["put", server, file, name] => exit_on_error(client::put(server, file, Some(name))),
+        ["ls", server] => exit_on_error(client::list(server, "")),
+        ["ls", server, dir] => exit_on_error(client::list(server, dir)),
+        ["stat", server, name] => exit_on_error(client::stat(server, name)),
+        ["rm", server, name] => exit_on_error(client::delete(server, name)),
+        _ => {
+            print_usage();
+            Ok(())
+        }
+    }
+}
+
+fn exit_on_error(result: Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = result {
+        eprintln!("💀 {}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn serve() -> Result<(), Box<dyn Error>> {
+    let server = Arc::new(Server {
+        root: ShareRoot::from_env()?,
+        checksums: Checksums::default(),
+        token: env::var("SHARE_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+        uploads: AtomicU64::new(0),
+        limits: Limits::from_env()?,
+    });
     let listener = match TcpListener::bind("0.0.0.0:8888") {
         Ok(listener) => listener,
         Err(_) => TcpListener::bind("0.0.0.0:0").expect("Unable to assign any port."),
@@ -13,9 +86,16 @@ fn main() -> Result<(), Box<dyn Error>> {
         "🚀 Listening at: http://127.0.0.1:{}",
         listener.local_addr()?.port()
     );
+    println!("📁 Sharing: {}", server.root.path().display());
+    if server.token.is_none() {
+        println!("🔒 No SHARE_TOKEN set, uploads and deletes are disabled");
+    }
     for stream in listener.incoming().flatten() {
+        let server = Arc::clone(&server);
+        let slot = server.limits.connection_slot();
         thread::spawn(move || {
-            if let Err(e) = handle_conn(stream) {
+            let _slot = slot;
+            if let Err(e) = server.handle_conn(stream) {
                 println!("💀 Connectione error: {}", e);
             }
         });
@@ -23,33 +103,274 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn handle_conn(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut filename = String::new();
+impl Server {
+    /// Reads one request header and carries it out. See [`Command`] for the
+    /// replies; any of them is `ERR <code> <message>` if the request is
+    /// refused.
+    fn handle_conn(&self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(self.limits.idle_timeout())?;
+        stream.set_write_timeout(self.limits.idle_timeout())?;
+        let client = stream.peer_addr()?.ip();
+        let mut reader = BufReader::new(self.limits.throttle(client, stream.try_clone()?));
+        let mut stream = self.limits.throttle(client, stream);
+        let request = match Request::read(&mut reader) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("⛔ Bad request from {}: {}", client, e);
+                Refusal::BadRequest(e).send(&mut stream)?;
+                return Ok(());
+            }
+        };
+        println!("📃 {}", request.command);
 
-    reader.read_line(&mut filename)?;
-    let filename = filename.trim();
-    println!("📃 File requsted: {}", filename);
+        let result = match self.authorize(&request) {
+            Err(refusal) => Err(refusal.into()),
+            Ok(()) => {
+                let _slot = match request.command.transfers() {
+                    true => self.limits.transfer_slot(),
+                    false => None,
+                };
+                match &request.command {
+                    Command::Get { name, offset } => self.send_file(&mut stream, name, *offset),
+                    Command::Put { name, size, sha256 } => {
+                        self.receive_file(&mut reader, &mut stream, name, *size, sha256)
+                    }
+                    Command::List { dir } => self.list(&mut stream, dir),
+                    Command::Stat { name } => self.stat(&mut stream, name),
+                    Command::Delete { name } => self.delete(&mut stream, name),
+                    Command::Archive { dir, zstd } => self.archive(&mut stream, dir, *zstd),
+                }
+            }
+        };
+        match result.map_err(|e| e.downcast::<Refusal>()) {
+            Ok(()) => Ok(()),
+            Err(Ok(refusal)) => {
+                refusal.send(&mut stream)?;
+                println!("⛔ {} refused: {}", request.command, refusal);
+                Ok(())
+            }
+            Err(Err(e)) => Err(e),
+        }
+    }
 
-    match File::open(filename) {
-        Ok(mut file) => {
-            let filesize = file.metadata()?.len();
-            writeln!(stream, "{}", filesize)?;
+    fn authorize(&self, request: &Request) -> Result<(), Refusal> {
+        match (&self.token, &request.token) {
+            (Some(token), Some(given)) if same_token(token, given) => Ok(()),
+            (Some(_), _) => Err(Refusal::Unauthorized),
+            (None, _) if request.command.writes() => Err(Refusal::Unauthorized),
+            (None, _) => Ok(()),
+        }
+    }
 
-            let mut buffer = [0; 4096];
-            loop {
-                let n = file.read(&mut buffer)?;
-                if n == 0 {
-                    break;
-                }
-                stream.write_all(&buffer[..n])?;
+    fn send_file(
+        &self,
+        stream: &mut impl Write,
+        name: &str,
+        offset: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve(name)?;
+        let mut file = File::open(&path).map_err(|_| Refusal::NotFound)?;
+        let metadata = file.metadata()?;
+        let filesize = metadata.len();
+        if offset > filesize {
+            return Err(Refusal::BadOffset {
+                offset,
+                size: filesize,
             }
-            println!("✅ File has been sent!");
+            .into());
         }
-        Err(_) => {
-            writeln!(stream, "0")?;
-            println!("💀 File: {} not found!", filename);
+        let hash = self.checksums.get(&path, &metadata)?;
+        writeln!(stream, "OK {} {}", filesize, hash)?;
+        file.seek(SeekFrom::Start(offset))?;
+        if offset > 0 {
+            println!("⏩ Resuming at byte {}", offset);
         }
+
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..n])?;
+        }
+        println!("✅ File has been sent!");
+        Ok(())
+    }
+
+    /// Stores an upload next to its destination first, and only gives it
+    /// its name once all of it arrived and its hash matches.
+    fn receive_file(
+        &self,
+        reader: &mut impl BufRead,
+        stream: &mut impl Write,
+        name: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve_new(name)?;
+        let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(Refusal::BadRequest("Invalid file name".to_string()).into());
+        };
+        fs::create_dir_all(dir)?;
+        let upload = self.uploads.fetch_add(1, Ordering::Relaxed);
+        let temp = dir.join(format!(
+            ".{}.{}.upload",
+            file_name.to_string_lossy(),
+            upload
+        ));
+        writeln!(stream, "READY")?;
+
+        let result = receive_into(reader, &temp, size, sha256);
+        if result.is_err() {
+            fs::remove_file(&temp).ok();
+        }
+        result?;
+        fs::rename(&temp, &path)?;
+        writeln!(stream, "OK {} {}", size, sha256)?;
+        println!("📥 Stored {} ({} bytes)", name, size);
+        Ok(())
+    }
+
+    fn list(&self, stream: &mut impl Write, dir: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve_dir(dir)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            // Names that don't fit on a reply line can't be asked for either.
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.contains(['\r', '\n']) || !self.root.is_visible(&entry.path()) {
+                continue;
+            }
+            let metadata = fs::metadata(entry.path())?;
+            let (kind, size) = match metadata.is_dir() {
+                true => ("d", 0),
+                false if metadata.is_file() => ("f", metadata.len()),
+                false => continue,
+            };
+            entries.push((name, kind, size, mtime(&metadata)));
+        }
+        entries.sort();
+
+        writeln!(stream, "OK {}", entries.len())?;
+        for (name, kind, size, mtime) in entries {
+            writeln!(stream, "{} {} {} {}", kind, size, mtime, name)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, stream: &mut impl Write, name: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve_entry(name)?;
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            writeln!(stream, "OK d 0 {} -", mtime(&metadata))?;
+        } else if metadata.is_file() {
+            let hash = self.checksums.get(&path, &metadata)?;
+            writeln!(
+                stream,
+                "OK f {} {} {}",
+                metadata.len(),
+                mtime(&metadata),
+                hash
+            )?;
+        } else {
+            return Err(Refusal::NotAFile.into());
+        }
+        Ok(())
+    }
+
+    fn delete(&self, stream: &mut impl Write, name: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve_for_delete(name)?;
+        fs::remove_file(&path)?;
+        writeln!(stream, "OK")?;
+        println!("🗑️ Deleted {}", name);
+        Ok(())
+    }
+
+    /// Streams a directory as a tar, never holding more of it than the
+    /// file being sent. The entry count goes ahead in the reply, so the
+    /// client can tell a complete archive from a cut connection.
+    fn archive(
+        &self,
+        stream: &mut impl Write,
+        dir: &str,
+        zstd: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = self.root.resolve_dir(dir)?;
+        let entries = archive::collect(&self.root, &path)?;
+        let format = if zstd { "tar+zstd" } else { "tar" };
+        writeln!(stream, "OK {} {}", format, entries.len())?;
+
+        let writer = BufWriter::new(stream);
+        let bytes = if zstd {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            let bytes = archive::write(&entries, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            bytes
+        } else {
+            archive::write(&entries, writer)?
+        };
+        println!("📦 Sent {} entries ({} bytes)", entries.len(), bytes);
+        Ok(())
+    }
+}
+
+/// Copies `size` bytes of an upload into `temp` and checks their hash.
+fn receive_into(
+    reader: &mut impl BufRead,
+    temp: &Path,
+    size: u64,
+    sha256: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(temp)?;
+    let received = io::copy(&mut reader.take(size), &mut file)?;
+    if received < size {
+        return Err(Refusal::BadUpload(format!("Received {} of {} bytes", received, size)).into());
+    }
+    file.sync_all()?;
+    if sha256_file(temp)? != sha256 {
+        return Err(Refusal::BadUpload("Checksum mismatch".to_string()).into());
     }
     Ok(())
 }
+
+/// Compares tokens in constant time, so their content can't be guessed
+/// from how long a refusal takes.
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn mtime(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+fn print_usage() {
+    println!("Usage:");
+    println!("  tcp_file_share [serve]                    - Share SHARE_ROOT on port 8888");
+    println!("  tcp_file_share get <host:port> <name> [output]");
+    println!("                                            - Download a file, resuming a .part");
+    println!("  tcp_file_share getdir [--zstd] <host:port> <dir> [output]");
+    println!("                                            - Download a directory as a tar stream");
+    println!("  tcp_file_share put <host:port> <file> [name]");
+    println!("                                            - Upload a file");
+    println!("  tcp_file_share ls <host:port> [dir]       - List a directory");
+    println!("  tcp_file_share stat <host:port> <name>    - Show a file's size, time and hash");
+    println!("  tcp_file_share rm <host:port> <name>      - Delete a file");
+    println!("SHARE_TOKEN is sent with every request, and required by the server if it");
+    println!("has one. Uploads and deletes need it.");
+    println!("The server's bandwidth is capped by SHARE_RATE_LIMIT and SHARE_CLIENT_RATE_LIMIT");
+    println!("(bytes/sec, e.g. 512K), its concurrent transfers by SHARE_MAX_TRANSFERS and");
+    println!("clients by SHARE_MAX_CONNECTIONS (64). SHARE_IDLE_TIMEOUT drops clients silent");
+    println!("for that many seconds (30, 0 to wait forever).");
+}
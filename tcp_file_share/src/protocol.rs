@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+// Caps what a client can make the server buffer before the body.
+const MAX_HEADER_LEN: u64 = 8 * 1024;
+
+/// What a client asks for. It is sent as a header: the command on the
+/// first line, then `key: value` lines, then an empty line.
+pub enum Command {
+    /// Replied to with `OK <size> <sha256>` and the bytes from `offset` on.
+    Get { name: String, offset: u64 },
+    /// Replied to with `READY`, after which the client sends `size` bytes,
+    /// and then with `OK <size> <sha256>` once they are stored.
+    Put {
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Replied to with `OK <count>` and one `<f|d> <size> <mtime> <name>`
+    /// line per entry.
+    List { dir: String },
+    /// Replied to with `OK <f|d> <size> <mtime> <sha256>`, where a
+    /// directory has no hash but `-`.
+    Stat { name: String },
+    /// Replied to with `OK`.
+    Delete { name: String },
+    /// Replied to with `OK <tar|tar+zstd> <entries>` and the directory as
+    /// a tar stream, built as it is sent, until the connection closes.
+    Archive { dir: String, zstd: bool },
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "GET",
+            Command::Put { .. } => "PUT",
+            Command::List { .. } => "LIST",
+            Command::Stat { .. } => "STAT",
+            Command::Delete { .. } => "DELETE",
+            Command::Archive { .. } => "ARCHIVE",
+        }
+    }
+
+    /// Whether the command moves file contents, and so takes a transfer
+    /// slot.
+    pub fn transfers(&self) -> bool {
+        matches!(
+            self,
+            Command::Get { .. } | Command::Put { .. } | Command::Archive { .. }
+        )
+    }
+
+    /// Whether the command changes the share.
+    pub fn writes(&self) -> bool {
+        matches!(self, Command::Put { .. } | Command::Delete { .. })
+    }
+}
+
+/// One line per command for the server log.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Get { name, offset: 0 } => write!(f, "GET {}", name),
+            Command::Get { name, offset } => write!(f, "GET {} from byte {}", name, offset),
+            Command::Put { name, size, .. } => write!(f, "PUT {} ({} bytes)", name, size),
+            Command::List { dir } if dir.is_empty() => write!(f, "LIST /"),
+            Command::List { dir } => write!(f, "LIST {}", dir),
+            Command::Stat { name } => write!(f, "STAT {}", name),
+            Command::Delete { name } => write!(f, "DELETE {}", name),
+            Command::Archive { dir, zstd } => {
+                let dir = if dir.is_empty() { "/" } else { dir };
+                match zstd {
+                    true => write!(f, "ARCHIVE {} (zstd)", dir),
+                    false => write!(f, "ARCHIVE {}", dir),
+                }
+            }
+        }
+    }
+}
+
+pub struct Request {
+    /// The shared token, if the client has one.
+    pub token: Option<String>,
+    pub command: Command,
+}
+
+impl Request {
+    pub fn read(reader: &mut impl BufRead) -> Result<Request, String> {
+        let mut remaining = MAX_HEADER_LEN;
+        let command = read_line(reader, &mut remaining)?;
+        let mut fields = Vec::new();
+        loop {
+            let line = read_line(reader, &mut remaining)?;
+            if line.is_empty() {
+                break;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid header line: {}", line))?;
+            fields.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        let field = |key: &str| {
+            fields
+                .iter()
+                .rev()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        let required = |key: &str| field(key).ok_or_else(|| format!("Missing {}", key));
+        let number = |key: &str| -> Result<u64, String> {
+            required(key)?
+                .parse()
+                .map_err(|_| format!("Invalid {}", key))
+        };
+
+        let command = match command.as_str() {
+            "GET" => Command::Get {
+                name: required("name")?,
+                offset: match field("offset") {
+                    Some(_) => number("offset")?,
+                    None => 0,
+                },
+            },
+            "PUT" => {
+                let sha256 = required("sha256")?.to_lowercase();
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err("Invalid sha256".to_string());
+                }
+                Command::Put {
+                    name: required("name")?,
+                    size: number("size")?,
+                    sha256,
+                }
+            }
+            "LIST" => Command::List {
+                dir: field("name").unwrap_or_default(),
+            },
+            "STAT" => Command::Stat {
+                name: required("name")?,
+            },
+            "DELETE" => Command::Delete {
+                name: required("name")?,
+            },
+            "ARCHIVE" => Command::Archive {
+                dir: field("name").unwrap_or_default(),
+                zstd: match field("compression").as_deref() {
+                    None | Some("none") => false,
+                    Some("zstd") => true,
+                    Some(other) => return Err(format!("Unknown compression: {}", other)),
+                },
+            },
+            _ => return Err(format!("Unknown command: {}", command)),
+        };
+        Ok(Request {
+            token: field("token"),
+            command,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut fields = Vec::new();
+        if let Some(token) = &self.token {
+            fields.push(("token", token.clone()));
+        }
+        match &self.command {
+            Command::Get { name, offset } => {
+                fields.push(("name", name.clone()));
+                fields.push(("offset", offset.to_string()));
+            }
+            Command::Put { name, size, sha256 } => {
+                fields.push(("name", name.clone()));
+                fields.push(("size", size.to_string()));
+                fields.push(("sha256", sha256.clone()));
+            }
+            Command::List { dir } => fields.push(("name", dir.clone())),
+            Command::Stat { name } | Command::Delete { name } => {
+                fields.push(("name", name.clone()))
+            }
+            Command::Archive { dir, zstd } => {
+                fields.push(("name", dir.clone()));
+                if *zstd {
+                    fields.push(("compression", "zstd".to_string()));
+                }
+            }
+        }
+
+        let mut header = format!("{}\n", self.command.name());
+        for (key, value) in fields {
+            if value.contains(['\r', '\n']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} can't contain a line break", key),
+                ));
+            }
+            header.push_str(&format!("{}: {}\n", key, value));
+        }
+        header.push('\n');
+        writer.write_all(header.as_bytes())?;
+        writer.flush()
+    }
+}
+
+/// Reads one header line out of the `remaining` header budget.
+fn read_line(reader: &mut impl BufRead, remaining: &mut u64) -> Result<String, String> {
+    let mut line = String::new();
+    let n = reader
+        .take(*remaining)
+        .read_line(&mut line)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                "Timed out waiting for the request".to_string()
+            }
+            _ => e.to_string(),
+        })?;
+    *remaining -= n as u64;
+    if !line.ends_with('\n') {
+        return Err("Incomplete header".to_string());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Why a request was turned away, sent as `ERR <code> <message>`.
+#[derive(Debug)]
+pub enum Refusal {
+    BadRequest(String),
+    /// The token is missing or wrong, or the server has none and the
+    /// command writes.
+    Unauthorized,
+    NotFound,
+    /// Outside the share root, through a symlink, or not allowed by the
+    /// allow and deny lists.
+    Denied,
+    NotAFile,
+    NotADirectory,
+    BadOffset {
+        offset: u64,
+        size: u64,
+    },
+    /// An upload ended early or did not match its announced hash.
+    BadUpload(String),
+}
+
+impl Refusal {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Refusal::BadRequest(_) => "BAD_REQUEST",
+            Refusal::Unauthorized => "UNAUTHORIZED",
+            Refusal::NotFound => "NOT_FOUND",
+            Refusal::Denied => "DENIED",
+            Refusal::NotAFile => "NOT_A_FILE",
+            Refusal::NotADirectory => "NOT_A_DIRECTORY",
+            Refusal::BadOffset { .. } => "BAD_OFFSET",
+            Refusal::BadUpload(_) => "BAD_UPLOAD",
+        }
+    }
+
+    pub fn send(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "ERR {} {}", self.code(), self)
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::BadRequest(reason) => write!(f, "{}", reason),
+            Refusal::Unauthorized => write!(f, "Invalid or missing token"),
+            Refusal::NotFound => write!(f, "File not found"),
+            Refusal::Denied => write!(f, "Access denied"),
+            Refusal::NotAFile => write!(f, "Not a regular file"),
+            Refusal::NotADirectory => write!(f, "Not a directory"),
+            Refusal::BadOffset { offset, size } => write!(
+                f,
+                "Offset {} is past the end of the file ({} bytes)",
+                offset, size
+            ),
+            Refusal::BadUpload(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for Refusal {}
+
+/// An `ERR` reply as seen by the client.
+#[derive(Debug)]
+pub struct ErrorReply {
+    pub code: String,
+    pub message: String,
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server: {} {}", self.code, self.message)
+    }
+}
+
+impl Error for ErrorReply {}
+
+/// Reads a reply line. Returns what follows `OK`, split on spaces, or the
+/// server's `ERR` as an [`ErrorReply`].
+pub fn read_reply(reader: &mut impl BufRead) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let line = line.trim_end_matches(['\r', '\n']);
+    if let Some(error) = line.strip_prefix("ERR ") {
+        let (code, message) = error.split_once(' ').unwrap_or((error, ""));
+        return Err(ErrorReply {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+        .into());
+    }
+    match line.split_once(' ') {
+        Some(("OK", fields)) => Ok(fields.split(' ').map(str::to_string).collect()),
+        None if line == "OK" || line == "READY" => Ok(Vec::new()),
+        _ => Err(format!("Unexpected reply: {}", line).into()),
+    }
+}
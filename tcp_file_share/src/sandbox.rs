@@ -0,0 +1,230 @@
+use crate::protocol::Refusal;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The directory clients can read from, and which files in it they may
+/// see.
+pub struct ShareRoot {
+    root: PathBuf,
+    allow_symlinks: bool,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl ShareRoot {
+    /// Reads the settings from the environment:
+    ///
+    /// * `SHARE_ROOT`: the shared directory, the working directory by default.
+    /// * `SHARE_ALLOW_SYMLINKS`: follow symlinks that stay inside the root.
+    /// * `SHARE_ALLOW`: comma separated patterns; if set, only matching files
+    ///   are served.
+    /// * `SHARE_DENY`: comma separated patterns that are never served, `.*`
+    ///   by default so dotfiles stay private.
+    ///
+    /// Patterns may use `*` and `?`. One without a `/` is matched against
+    /// each part of the path, one with a `/` against the whole path.
+    pub fn from_env() -> Result<Self, String> {
+        let root = env::var("SHARE_ROOT").unwrap_or_else(|_| ".".to_string());
+        let root = fs::canonicalize(&root)
+            .map_err(|e| format!("SHARE_ROOT: can't open {}: {}", root, e))?;
+        if !root.is_dir() {
+            return Err(format!("SHARE_ROOT: {} is not a directory", root.display()));
+        }
+        let allow_symlinks = env::var("SHARE_ALLOW_SYMLINKS")
+            .is_ok_and(|value| matches!(value.as_str(), "1" | "true" | "yes"));
+        let allow = patterns(&env::var("SHARE_ALLOW").unwrap_or_default());
+        let deny = patterns(&env::var("SHARE_DENY").unwrap_or_else(|_| ".*".to_string()));
+        Ok(ShareRoot {
+            root,
+            allow_symlinks,
+            allow,
+            deny,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps a requested name to a file inside the root.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, Refusal> {
+        let path = self.locate(name, self.allow_symlinks)?;
+        if !path.is_file() {
+            return Err(Refusal::NotAFile);
+        }
+        Ok(path)
+    }
+
+    /// Like `resolve`, for a directory. An empty name is the root.
+    pub fn resolve_dir(&self, name: &str) -> Result<PathBuf, Refusal> {
+        let path = self.locate(name, self.allow_symlinks)?;
+        if !path.is_dir() {
+            return Err(Refusal::NotADirectory);
+        }
+        Ok(path)
+    }
+
+    /// A file or a directory.
+    pub fn resolve_entry(&self, name: &str) -> Result<PathBuf, Refusal> {
+        self.locate(name, self.allow_symlinks)
+    }
+
+    /// A file to delete. Writes never go through a symlink, so the link
+    /// can't be used to reach its target.
+    pub fn resolve_for_delete(&self, name: &str) -> Result<PathBuf, Refusal> {
+        let path = self.locate(name, false)?;
+        if !path.is_file() {
+            return Err(Refusal::NotAFile);
+        }
+        Ok(path)
+    }
+
+    /// Where an upload to `name` goes. Directories on the way that don't
+    /// exist yet are for the caller to create.
+    pub fn resolve_new(&self, name: &str) -> Result<PathBuf, Refusal> {
+        let relative: PathBuf = requested_path(name)?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let parts = relative.components().count();
+        if parts == 0 {
+            return Err(Refusal::BadRequest("Missing file name".to_string()));
+        }
+
+        let mut path = self.root.clone();
+        for (i, component) in relative.components().enumerate() {
+            path.push(component);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => return Err(Refusal::Denied),
+                Ok(metadata) if i + 1 < parts && !metadata.is_dir() => {
+                    return Err(Refusal::NotADirectory);
+                }
+                Ok(metadata) if i + 1 == parts && !metadata.is_file() => {
+                    return Err(Refusal::NotAFile);
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(_) => return Err(Refusal::Denied),
+            }
+        }
+        if !self.permits(&relative, true) {
+            return Err(Refusal::Denied);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Whether an entry of a resolved directory shows up in its listing.
+    pub fn is_visible(&self, path: &Path) -> bool {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return false;
+        };
+        if metadata.file_type().is_symlink() && !self.allow_symlinks {
+            return false;
+        }
+        let Ok(path) = fs::canonicalize(path) else {
+            return false;
+        };
+        path.strip_prefix(&self.root)
+            .is_ok_and(|relative| self.permits(relative, path.is_file()))
+    }
+
+    /// Finds an existing file or directory, checked one part at a time so
+    /// a symlinked directory is caught too.
+    fn locate(&self, name: &str, follow_symlinks: bool) -> Result<PathBuf, Refusal> {
+        let mut path = self.root.clone();
+        for component in requested_path(name)?.components() {
+            path.push(component);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_symlink() && !follow_symlinks => {
+                    return Err(Refusal::Denied);
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Refusal::NotFound),
+                Err(_) => return Err(Refusal::Denied),
+            }
+        }
+
+        // An allowed symlink still has to lead somewhere inside the root.
+        let path = fs::canonicalize(&path).map_err(|_| Refusal::NotFound)?;
+        let relative = path.strip_prefix(&self.root).map_err(|_| Refusal::Denied)?;
+        if !self.permits(relative, path.is_file()) {
+            return Err(Refusal::Denied);
+        }
+        Ok(path)
+    }
+
+    /// Checks a path relative to the root against the deny list, and a file
+    /// also against the allow list.
+    fn permits(&self, relative: &Path, is_file: bool) -> bool {
+        let parts: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let relative = parts.join("/");
+        let matches = |pattern: &String| {
+            if pattern.contains('/') {
+                glob(pattern, &relative)
+            } else {
+                parts.iter().any(|part| glob(pattern, part))
+            }
+        };
+        !self.deny.iter().any(matches)
+            && (!is_file || self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+/// A requested name as a path that stays below the root.
+fn requested_path(name: &str) -> Result<&Path, Refusal> {
+    if name.contains('\0') {
+        return Err(Refusal::BadRequest("Invalid file name".to_string()));
+    }
+    let path = Path::new(name);
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Refusal::Denied);
+    }
+    Ok(path)
+}
+
+fn patterns(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for one.
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -1,5 +1,170 @@
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
+#[allow(dead_code)]
+#[path = "src/resp.rs"]
+mod resp;
+
+use resp::Value;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+
+const USAGE: &str = "Usage: client [--host <host>] [--port <port>]
+              [--pipe <file>|-] [--eval <file>]";
+const HISTORY_FILE: &str = ".tcp_redis_history";
+
+/// Command names offered by tab completion.
+const COMMANDS: &[&str] = &[
+    "APPEND",
+    "BGREWRITEAOF",
+    "BGSAVE",
+    "CLIENT",
+    "COMMAND",
+    "DECR",
+    "DECRBY",
+    "DEL",
+    "DISCARD",
+    "ECHO",
+    "EXEC",
+    "EXPIRE",
+    "FLUSHALL",
+    "FLUSHDB",
+    "GET",
+    "GETSET",
+    "HDEL",
+    "HELLO",
+    "HGET",
+    "HGETALL",
+    "HINCRBY",
+    "HSET",
+    "INCR",
+    "INCRBY",
+    "INFO",
+    "LLEN",
+    "LPOP",
+    "LPUSH",
+    "LRANGE",
+    "MONITOR",
+    "MULTI",
+    "PERSIST",
+    "PEXPIRE",
+    "PEXPIREAT",
+    "PING",
+    "PSUBSCRIBE",
+    "PTTL",
+    "PUBLISH",
+    "PUBSUB",
+    "PUNSUBSCRIBE",
+    "QUIT",
+    "REPLICAOF",
+    "RPOP",
+    "RPUSH",
+    "SADD",
+    "SAVE",
+    "SELECT",
+    "SET",
+    "SINTER",
+    "SLOWLOG",
+    "SMEMBERS",
+    "SREM",
+    "SUBSCRIBE",
+    "SUNION",
+    "TTL",
+    "TYPE",
+    "UNSUBSCRIBE",
+    "UNWATCH",
+    "WATCH",
+    "ZADD",
+    "ZINCRBY",
+    "ZRANGE",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+];
+
+struct Options {
+    host: String,
+    port: u16,
+    pipe: Option<String>,
+    eval: Option<PathBuf>,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut options = Options {
+            host: "127.0.0.1".to_string(),
+            port: 6789,
+            pipe: None,
+            eval: None,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(option) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", option))?;
+            match option.as_str() {
+                "--host" => options.host = value,
+                "--port" => {
+                    options.port = value
+                        .parse()
+                        .map_err(|_| format!("Invalid value for {}: {}", option, value))?
+                }
+                "--pipe" => options.pipe = Some(value),
+                "--eval" => options.eval = Some(PathBuf::from(value)),
+                _ => return Err(format!("Unknown option: {}", option)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Completes the command name, the first word of the line.
+struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        let start = typed.len() - typed.trim_start().len();
+        let word = &typed[start..];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let prefix = word.to_uppercase();
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(&prefix))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
 
 struct RedisClient {
     stream: TcpStream,
@@ -14,43 +179,93 @@ impl RedisClient {
         Ok(RedisClient { stream, reader })
     }
 
-    fn send_command(&mut self, command: &str) -> io::Result<String> {
-        writeln!(self.stream, "{}", command)?;
+    fn send_command(&mut self, args: &[Vec<u8>]) -> io::Result<Value> {
+        self.stream.write_all(&Value::command(args).encode(2))?;
         self.stream.flush()?;
 
-        let mut response = String::new();
-        self.reader.read_line(&mut response)?;
+        self.read_reply()
+    }
 
-        Ok(response.trim().to_string())
+    fn read_reply(&mut self) -> io::Result<Value> {
+        resp::read_value(&mut self.reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "server closed"))
     }
 
-    fn run(&mut self) -> io::Result<()> {
+    /// After a successful (P)SUBSCRIBE or MONITOR the connection only carries
+    /// messages, so keep printing them until the server goes away.
+    fn read_messages(&mut self) -> io::Result<()> {
+        println!("Reading messages... (press Ctrl-C to quit)");
+        loop {
+            let message = self.read_reply()?;
+            println!("{}", format_reply(&message, 0));
+        }
+    }
+
+    /// Runs one line typed at the prompt or read by `--eval`. Returns false
+    /// once the session is over.
+    fn execute_line(&mut self, line: &str) -> bool {
+        let args = match resp::split_args(line.as_bytes()) {
+            Some(args) if args.is_empty() => return true,
+            Some(args) => args,
+            None => {
+                eprintln!("Invalid argument(s)");
+                return true;
+            }
+        };
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        if name == "QUIT" || name == "EXIT" {
+            println!("Bye!");
+            return false;
+        }
+
+        match self.send_command(&args) {
+            Ok(response)
+                if matches!(name.as_str(), "SUBSCRIBE" | "PSUBSCRIBE" | "MONITOR")
+                    && !matches!(response, Value::Error(_)) =>
+            {
+                println!("{}", format_reply(&response, 0));
+                if let Err(e) = self.read_messages() {
+                    eprintln!("Error: {}", e);
+                }
+                false
+            }
+            Ok(response) => {
+                println!("{}", format_reply(&response, 0));
+                true
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                false
+            }
+        }
+    }
+
+    fn run(&mut self, prompt: &str) -> io::Result<()> {
         println!("Connected to Redis server. Type 'QUIT' to exit.");
 
+        let mut editor: Editor<CommandCompleter, DefaultHistory> =
+            Editor::new().map_err(io::Error::other)?;
+        editor.set_helper(Some(CommandCompleter));
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(history) = &history {
+            // A missing history file just means this is the first session.
+            let _ = editor.load_history(history);
+        }
+
         loop {
-            print!("redis> ");
-            io::stdout().flush()?;
-
-            let mut input = String::new();
-            match io::stdin().read_line(&mut input) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let command = input.trim();
-                    if command.is_empty() {
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    let line = line.trim();
+                    if line.is_empty() {
                         continue;
                     }
-                    if command.to_uppercase() == "QUIT" || command.to_uppercase() == "EXIT" {
-                        println!("Bye!");
+                    let _ = editor.add_history_entry(line);
+                    if !self.execute_line(line) {
                         break;
                     }
-                    match self.send_command(command) {
-                        Ok(response) => println!("{}", response),
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            break;
-                        }
-                    }
                 }
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
                 Err(e) => {
                     eprintln!("Error reading input: {}", e);
                     break;
@@ -58,20 +273,217 @@ impl RedisClient {
             }
         }
 
+        if let Some(history) = &history
+            && let Err(e) = editor.save_history(history)
+        {
+            eprintln!("Error saving history: {}", e);
+        }
         Ok(())
     }
+
+    /// `--eval`: runs the commands in `path` one after the other, printing
+    /// each reply. Blank lines and lines starting with `#` are skipped.
+    fn eval(&mut self, path: &PathBuf) -> io::Result<()> {
+        let file = BufReader::new(File::open(path)?);
+        for line in file.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !self.execute_line(line) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// `--pipe`: streams every command from `input` without waiting for
+    /// replies, which are read back concurrently. The input may hold RESP
+    /// or inline commands. An ECHO of a random marker goes last, so its
+    /// reply tells us everything before it has been answered. Input that
+    /// doesn't parse ends the stream there, and is reported with its line.
+    fn pipe(&mut self, input: Box<dyn BufRead + Send>) -> io::Result<()> {
+        let mut marker = String::new();
+        for _ in 0..2 {
+            marker.push_str(&format!(
+                "{:016x}",
+                RandomState::new().build_hasher().finish()
+            ));
+        }
+        let echo = Value::command(&[b"ECHO".to_vec(), marker.clone().into_bytes()]);
+
+        let mut input = CountLines {
+            inner: input,
+            lines: 0,
+        };
+        let mut writer = BufWriter::new(self.stream.try_clone()?);
+        let sender = thread::spawn(move || -> io::Result<()> {
+            let sent = send_commands(&mut input, &mut writer);
+            // The marker still goes out after a bad line, so the replies to
+            // what was sent before it are counted. Closing our half makes the
+            // server hang up should it never arrive.
+            let ended = writer
+                .write_all(&echo.encode(2))
+                .and_then(|_| writer.flush());
+            let _ = writer.get_ref().shutdown(Shutdown::Write);
+            sent.and(ended)
+        });
+
+        let received = self.read_replies(&marker);
+        let sent = sender
+            .join()
+            .map_err(|_| io::Error::other("pipe sender panicked"))?;
+        if let Ok((errors, replies)) = received {
+            println!("errors: {}, replies: {}", errors, replies);
+        }
+        sent?;
+        received?;
+        println!("All data transferred. Last reply received from server.");
+        Ok(())
+    }
+
+    /// Reads replies up to the ECHO of `marker`, returning how many were
+    /// errors and how many there were in all.
+    fn read_replies(&mut self, marker: &str) -> io::Result<(usize, usize)> {
+        let mut replies = 0;
+        let mut errors = 0;
+        loop {
+            match self.read_reply()? {
+                Value::Bulk(data) if data == marker.as_bytes() => return Ok((errors, replies)),
+                Value::Error(e) => {
+                    eprintln!("{}", e);
+                    errors += 1;
+                }
+                _ => {}
+            }
+            replies += 1;
+        }
+    }
+}
+
+fn send_commands(input: &mut CountLines, writer: &mut impl Write) -> io::Result<()> {
+    loop {
+        let line = input.lines + 1;
+        let args = resp::read_command(input)
+            .map_err(|e| io::Error::new(e.kind(), format!("input line {}: {}", line, e)))?;
+        match args {
+            Some(args) => writer.write_all(&Value::command(&args).encode(2))?,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The `--pipe` input, counting the lines read so far for error messages.
+struct CountLines {
+    inner: Box<dyn BufRead + Send>,
+    lines: usize,
+}
+
+impl Read for CountLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.lines += buf[..n].iter().filter(|&&byte| byte == b'\n').count();
+        Ok(n)
+    }
+}
+
+impl BufRead for CountLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.lines += buf[..amt].iter().filter(|&&byte| byte == b'\n').count();
+        }
+        self.inner.consume(amt);
+    }
+}
+
+fn quote(data: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in data {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_list(items: &[&Value], indent: usize) -> String {
+    if items.is_empty() {
+        return "(empty array)".to_string();
+    }
+    let width = items.len().to_string().len();
+    let mut lines = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let prefix = format!("{:>width$}) ", i + 1, width = width);
+        let body = format_reply(item, indent + prefix.len());
+        let pad = if i == 0 { 0 } else { indent };
+        lines.push(format!("{}{}{}", " ".repeat(pad), prefix, body));
+    }
+    lines.join("\n")
+}
+
+fn format_reply(value: &Value, indent: usize) -> String {
+    match value {
+        Value::Simple(s) => s.clone(),
+        Value::Error(s) => format!("(error) {}", s),
+        Value::Integer(n) => format!("(integer) {}", n),
+        Value::Bulk(data) => quote(data),
+        Value::Null | Value::NullArray => "(nil)".to_string(),
+        Value::Array(items) | Value::Push(items) => {
+            format_list(&items.iter().collect::<Vec<_>>(), indent)
+        }
+        Value::Map(pairs) => {
+            let flat: Vec<&Value> = pairs.iter().flat_map(|(k, v)| [k, v]).collect();
+            format_list(&flat, indent)
+        }
+    }
 }
 
 fn main() -> io::Result<()> {
-    match RedisClient::connect("127.0.0.1:6789") {
-        Ok(mut client) => {
-            client.run()?;
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
         }
+    };
+    let addr = format!("{}:{}", options.host, options.port);
+
+    let mut client = match RedisClient::connect(&addr) {
+        Ok(client) => client,
         Err(e) => {
             eprintln!("Failed to connect to Redis server: {}", e);
-            eprintln!("Make sure the server is running on 127.0.0.1:6789");
+            eprintln!("Make sure the server is running on {}", addr);
+            std::process::exit(1);
         }
-    }
+    };
 
+    let result = if let Some(pipe) = options.pipe {
+        let input: io::Result<Box<dyn BufRead + Send>> = match pipe.as_str() {
+            "-" => Ok(Box::new(BufReader::new(io::stdin()))),
+            path => File::open(path).map(|file| Box::new(BufReader::new(file)) as _),
+        };
+        input.and_then(|input| client.pipe(input))
+    } else if let Some(path) = options.eval {
+        client.eval(&path)
+    } else {
+        client.run(&format!("{}> ", addr))
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
     Ok(())
 }
@@ -0,0 +1,89 @@
+use super::{Reply, check_arity, parse_int, wrong_args};
+use crate::db::Db;
+use crate::resp::Value;
+use std::collections::HashMap;
+
+type Hash = HashMap<Vec<u8>, Vec<u8>>;
+
+/// HSET key field value [field value ...]
+pub fn hset(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, None)?;
+    if !args.len().is_multiple_of(2) {
+        return Err(wrong_args(name));
+    }
+    let hash = db.write::<Hash>(&args[1], true)?.expect("created");
+    let added = args[2..]
+        .chunks(2)
+        .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+        .count();
+    db.touch(&args[1]);
+    Ok(Value::Integer(added as i64))
+}
+
+pub fn hget(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    Ok(
+        match db
+            .read::<Hash>(&args[1])?
+            .and_then(|hash| hash.get(&args[2]))
+        {
+            Some(value) => Value::bulk(value.clone()),
+            None => Value::Null,
+        },
+    )
+}
+
+/// HDEL key field [field ...]
+pub fn hdel(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, None)?;
+    let key = &args[1];
+    let Some(hash) = db.write::<Hash>(key, false)? else {
+        return Ok(Value::Integer(0));
+    };
+    let removed = args[2..]
+        .iter()
+        .filter(|field| hash.remove(*field).is_some())
+        .count();
+    if removed > 0 {
+        db.touch(key);
+        db.remove_if_empty(key);
+    }
+    Ok(Value::Integer(removed as i64))
+}
+
+pub fn hgetall(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    let pairs = db
+        .read::<Hash>(&args[1])?
+        .map(|hash| {
+            hash.iter()
+                .map(|(field, value)| (Value::bulk(field.clone()), Value::bulk(value.clone())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Value::Map(pairs))
+}
+
+/// HINCRBY key field increment
+pub fn hincrby(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, Some(4))?;
+    let increment = parse_int(&args[3])?;
+    let current = match db
+        .read::<Hash>(&args[1])?
+        .and_then(|hash| hash.get(&args[2]))
+    {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| Value::error("ERR hash value is not an integer"))?,
+        None => 0,
+    };
+    let updated = current
+        .checked_add(increment)
+        .ok_or_else(|| Value::error("ERR increment or decrement would overflow"))?;
+
+    let hash = db.write::<Hash>(&args[1], true)?.expect("created");
+    hash.insert(args[2].clone(), updated.to_string().into_bytes());
+    db.touch(&args[1]);
+    Ok(Value::Integer(updated))
+}
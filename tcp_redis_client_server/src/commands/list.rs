@@ -0,0 +1,92 @@
+use super::{Reply, check_arity, normalize_range, parse_int};
+use crate::db::Db;
+use crate::resp::Value;
+use std::collections::VecDeque;
+
+type List = VecDeque<Vec<u8>>;
+
+/// LPUSH/RPUSH key element [element ...]
+pub fn push(db: &mut Db, name: &str, args: &[Vec<u8>], front: bool) -> Reply {
+    check_arity(name, args, 3, None)?;
+    let list = db.write::<List>(&args[1], true)?.expect("created");
+    for element in &args[2..] {
+        if front {
+            list.push_front(element.clone());
+        } else {
+            list.push_back(element.clone());
+        }
+    }
+    let len = list.len();
+    db.touch(&args[1]);
+    Ok(Value::Integer(len as i64))
+}
+
+/// LPOP/RPOP key [count]
+pub fn pop(db: &mut Db, name: &str, args: &[Vec<u8>], front: bool) -> Reply {
+    check_arity(name, args, 2, Some(3))?;
+    let count = match args.get(2) {
+        Some(count) => {
+            let count = parse_int(count)?;
+            if count < 0 {
+                return Err(Value::error("ERR value is out of range, must be positive"));
+            }
+            Some(count as usize)
+        }
+        None => None,
+    };
+
+    let key = &args[1];
+    let Some(list) = db.write::<List>(key, false)? else {
+        return Ok(if count.is_some() {
+            Value::NullArray
+        } else {
+            Value::Null
+        });
+    };
+    let mut popped = Vec::new();
+    for _ in 0..count.unwrap_or(1) {
+        let element = if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        };
+        match element {
+            Some(element) => popped.push(Value::bulk(element)),
+            None => break,
+        }
+    }
+    if !popped.is_empty() {
+        db.touch(key);
+        db.remove_if_empty(key);
+    }
+
+    Ok(match count {
+        Some(_) => Value::Array(popped),
+        None => popped.pop().unwrap_or(Value::Null),
+    })
+}
+
+/// LRANGE key start stop
+pub fn lrange(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, Some(4))?;
+    let start = parse_int(&args[2])?;
+    let stop = parse_int(&args[3])?;
+    let list = match db.read::<List>(&args[1])? {
+        Some(list) => list,
+        None => return Ok(Value::Array(vec![])),
+    };
+    let items = match normalize_range(start, stop, list.len()) {
+        Some((from, to)) => list
+            .range(from..to)
+            .map(|element| Value::bulk(element.clone()))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Value::Array(items))
+}
+
+pub fn llen(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    let len = db.read::<List>(&args[1])?.map_or(0, |list| list.len());
+    Ok(Value::Integer(len as i64))
+}
@@ -0,0 +1,293 @@
+mod hash;
+mod list;
+mod set;
+mod sorted_set;
+mod strings;
+
+use crate::db::{Db, WrongType, now_ms};
+use crate::resp::Value;
+
+pub use sorted_set::format_score;
+
+type Reply = Result<Value, Value>;
+type Handler = fn(&mut Db, &str, &[Vec<u8>]) -> Reply;
+
+fn wrong_args(name: &str) -> Value {
+    Value::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn wrong_type() -> Value {
+    Value::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+impl From<WrongType> for Value {
+    fn from(_: WrongType) -> Self {
+        wrong_type()
+    }
+}
+
+fn syntax_error() -> Value {
+    Value::error("ERR syntax error")
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Value> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Value::error("ERR value is not an integer or out of range"))
+}
+
+fn check_arity(name: &str, args: &[Vec<u8>], min: usize, max: Option<usize>) -> Result<(), Value> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        return Err(wrong_args(name));
+    }
+    Ok(())
+}
+
+/// Commands that may modify the dataset and therefore need to be written to
+/// the append-only file.
+pub fn is_write(args: &[Vec<u8>]) -> bool {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    matches!(
+        name.as_str(),
+        "SET"
+            | "INCR"
+            | "DECR"
+            | "INCRBY"
+            | "DECRBY"
+            | "APPEND"
+            | "GETSET"
+            | "DEL"
+            | "EXPIRE"
+            | "PEXPIRE"
+            | "PEXPIREAT"
+            | "PERSIST"
+            | "FLUSHALL"
+            | "FLUSHDB"
+            | "LPUSH"
+            | "RPUSH"
+            | "LPOP"
+            | "RPOP"
+            | "HSET"
+            | "HDEL"
+            | "HINCRBY"
+            | "SADD"
+            | "SREM"
+            | "ZADD"
+            | "ZINCRBY"
+    )
+}
+
+/// Rewrites relative expiry times (SET EX/PX, EXPIRE, PEXPIRE) into absolute
+/// unix milliseconds, so replaying the command later from the AOF yields the
+/// same deadline. Arguments that do not parse are left untouched so the
+/// command itself reports the error.
+pub fn absolute_expiry(args: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let deadline = |amount: &[u8], unit: u64| {
+        parse_int(amount)
+            .ok()
+            .filter(|amount| *amount > 0)
+            .map(|amount| {
+                now_ms()
+                    .saturating_add((amount as u64).saturating_mul(unit))
+                    .to_string()
+                    .into_bytes()
+            })
+    };
+
+    let mut rewritten = args.to_vec();
+    match name.as_str() {
+        "SET" => {
+            for i in 3..args.len().saturating_sub(1) {
+                let unit = match String::from_utf8_lossy(&args[i]).to_uppercase().as_str() {
+                    "EX" => 1000,
+                    "PX" => 1,
+                    _ => continue,
+                };
+                if let Some(at) = deadline(&args[i + 1], unit) {
+                    rewritten[i] = b"PXAT".to_vec();
+                    rewritten[i + 1] = at;
+                }
+                break;
+            }
+        }
+        "EXPIRE" | "PEXPIRE" if args.len() == 3 => {
+            let unit = if name == "EXPIRE" { 1000 } else { 1 };
+            // Non-positive TTLs delete the key; keep them relative.
+            if let Some(at) = deadline(&args[2], unit) {
+                rewritten[0] = b"PEXPIREAT".to_vec();
+                rewritten[2] = at;
+            }
+        }
+        _ => {}
+    }
+    rewritten
+}
+
+fn lookup(name: &str) -> Option<Handler> {
+    Some(match name {
+        "PING" => ping,
+        "ECHO" => echo,
+        "GET" => strings::get,
+        "SET" => strings::set,
+        "INCR" | "INCRBY" => |db, name, args| strings::incr_by(db, name, args, 1),
+        "DECR" | "DECRBY" => |db, name, args| strings::incr_by(db, name, args, -1),
+        "APPEND" => strings::append,
+        "GETSET" => strings::getset,
+        "DEL" => del,
+        "TYPE" => type_of,
+        "EXPIRE" => |db, name, args| expire(db, name, args, 1000),
+        "PEXPIRE" => |db, name, args| expire(db, name, args, 1),
+        "TTL" => |db, name, args| ttl(db, name, args, 1000),
+        "PTTL" => |db, name, args| ttl(db, name, args, 1),
+        "PEXPIREAT" => pexpireat,
+        "PERSIST" => persist,
+        "FLUSHALL" | "FLUSHDB" => flushall,
+        "LPUSH" => |db, name, args| list::push(db, name, args, true),
+        "RPUSH" => |db, name, args| list::push(db, name, args, false),
+        "LPOP" => |db, name, args| list::pop(db, name, args, true),
+        "RPOP" => |db, name, args| list::pop(db, name, args, false),
+        "LRANGE" => list::lrange,
+        "LLEN" => list::llen,
+        "HSET" => hash::hset,
+        "HGET" => hash::hget,
+        "HDEL" => hash::hdel,
+        "HGETALL" => hash::hgetall,
+        "HINCRBY" => hash::hincrby,
+        "SADD" => set::sadd,
+        "SREM" => set::srem,
+        "SMEMBERS" => set::smembers,
+        "SINTER" => |db, name, args| set::combine(db, name, args, true),
+        "SUNION" => |db, name, args| set::combine(db, name, args, false),
+        "ZADD" => sorted_set::zadd,
+        "ZRANGE" => sorted_set::zrange,
+        "ZRANGEBYSCORE" => sorted_set::zrangebyscore,
+        "ZRANK" => sorted_set::zrank,
+        "ZINCRBY" => sorted_set::zincrby,
+        _ => return None,
+    })
+}
+
+/// Whether `name` (upper case) is a keyspace command `execute` can run.
+pub fn exists(name: &str) -> bool {
+    lookup(name).is_some()
+}
+
+/// Runs a keyspace command against `db`. The caller holds the lock, so each
+/// command is atomic with respect to every other client.
+pub fn execute(db: &mut Db, args: &[Vec<u8>]) -> Value {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let handler = match lookup(&name) {
+        Some(handler) => handler,
+        None => {
+            let rest: Vec<String> = args[1..]
+                .iter()
+                .map(|arg| format!("'{}'", String::from_utf8_lossy(arg)))
+                .collect();
+            return Value::error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                String::from_utf8_lossy(&args[0]),
+                rest.join(" ")
+            ));
+        }
+    };
+    handler(db, &name, args).unwrap_or_else(|error| error)
+}
+
+/// Turns Redis-style start/stop indexes (negative counts from the end) into
+/// a half-open range over a collection of `len` items.
+fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize + 1))
+}
+
+fn ping(_db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 1, Some(2))?;
+    Ok(match args.get(1) {
+        Some(message) => Value::bulk(message.clone()),
+        None => Value::Simple("PONG".to_string()),
+    })
+}
+
+fn echo(_db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    Ok(Value::bulk(args[1].clone()))
+}
+
+fn del(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, None)?;
+    let removed = args[1..].iter().filter(|key| db.remove(key)).count();
+    Ok(Value::Integer(removed as i64))
+}
+
+/// EXPIRE/PEXPIRE; `unit` is the number of milliseconds per argument unit.
+fn expire(db: &mut Db, name: &str, args: &[Vec<u8>], unit: i64) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    let amount = parse_int(&args[2])?;
+    let at = if amount <= 0 {
+        0
+    } else {
+        now_ms().saturating_add((amount as u64).saturating_mul(unit as u64))
+    };
+    Ok(Value::Integer(expire_key(db, &args[1], at) as i64))
+}
+
+fn pexpireat(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    let at = parse_int(&args[2])?.max(0) as u64;
+    Ok(Value::Integer(expire_key(db, &args[1], at) as i64))
+}
+
+/// Sets an absolute deadline; a deadline in the past deletes the key.
+fn expire_key(db: &mut Db, key: &[u8], at: u64) -> bool {
+    if at <= now_ms() {
+        return db.remove(key);
+    }
+    db.set_expiry(key, at)
+}
+
+fn ttl(db: &mut Db, name: &str, args: &[Vec<u8>], unit: u64) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    Ok(Value::Integer(match db.expiry(&args[1]) {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(at)) => {
+            let remaining = at.saturating_sub(now_ms());
+            ((remaining + unit / 2) / unit) as i64
+        }
+    }))
+}
+
+fn persist(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    Ok(Value::Integer(db.persist(&args[1]) as i64))
+}
+
+fn type_of(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    let kind = db.get(&args[1]).map_or("none", |object| object.type_name());
+    Ok(Value::Simple(kind.to_string()))
+}
+
+fn flushall(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 1, Some(2))?;
+    db.clear();
+    Ok(Value::ok())
+}
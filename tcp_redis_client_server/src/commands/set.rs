@@ -0,0 +1,66 @@
+use super::{Reply, check_arity};
+use crate::db::Db;
+use crate::resp::Value;
+use std::collections::HashSet;
+
+type Set = HashSet<Vec<u8>>;
+
+fn members(set: impl IntoIterator<Item = Vec<u8>>) -> Value {
+    Value::Array(set.into_iter().map(Value::bulk).collect())
+}
+
+/// SADD key member [member ...]
+pub fn sadd(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, None)?;
+    let set = db.write::<Set>(&args[1], true)?.expect("created");
+    let added = args[2..]
+        .iter()
+        .filter(|member| set.insert(member.to_vec()))
+        .count();
+    if added > 0 {
+        db.touch(&args[1]);
+    }
+    Ok(Value::Integer(added as i64))
+}
+
+/// SREM key member [member ...]
+pub fn srem(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, None)?;
+    let key = &args[1];
+    let Some(set) = db.write::<Set>(key, false)? else {
+        return Ok(Value::Integer(0));
+    };
+    let removed = args[2..]
+        .iter()
+        .filter(|member| set.remove(*member))
+        .count();
+    if removed > 0 {
+        db.touch(key);
+        db.remove_if_empty(key);
+    }
+    Ok(Value::Integer(removed as i64))
+}
+
+pub fn smembers(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    let set = db.read::<Set>(&args[1])?.cloned().unwrap_or_default();
+    Ok(members(set))
+}
+
+/// SINTER/SUNION key [key ...]; missing keys count as empty sets.
+pub fn combine(db: &mut Db, name: &str, args: &[Vec<u8>], intersect: bool) -> Reply {
+    check_arity(name, args, 2, None)?;
+    let mut result: Option<Set> = None;
+    for key in &args[1..] {
+        let set = db.read::<Set>(key)?.cloned().unwrap_or_default();
+        result = Some(match result {
+            None => set,
+            Some(acc) if intersect => acc.intersection(&set).cloned().collect(),
+            Some(mut acc) => {
+                acc.extend(set);
+                acc
+            }
+        });
+    }
+    Ok(members(result.unwrap_or_default()))
+}
@@ -0,0 +1,206 @@
+use super::{Reply, check_arity, normalize_range, parse_int, syntax_error};
+use crate::db::Db;
+use crate::resp::Value;
+use crate::zset::SortedSet;
+use std::ops::Bound;
+
+fn parse_score(arg: &[u8]) -> Result<f64, Value> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| Value::error("ERR value is not a valid float"))
+}
+
+/// Parses a ZRANGEBYSCORE bound: a score, "(score" for exclusive, or ±inf.
+fn parse_bound(arg: &[u8]) -> Result<Bound<f64>, Value> {
+    let error = || Value::error("ERR min or max is not a float");
+    match arg.strip_prefix(b"(") {
+        Some(rest) => parse_score(rest).map(Bound::Excluded).map_err(|_| error()),
+        None => parse_score(arg).map(Bound::Included).map_err(|_| error()),
+    }
+}
+
+pub fn format_score(score: f64) -> Vec<u8> {
+    score.to_string().into_bytes()
+}
+
+fn with_scores<'a>(items: impl Iterator<Item = (&'a [u8], f64)>, scores: bool) -> Value {
+    let mut reply = Vec::new();
+    for (member, score) in items {
+        reply.push(Value::bulk(member));
+        if scores {
+            reply.push(Value::bulk(format_score(score)));
+        }
+    }
+    Value::Array(reply)
+}
+
+/// ZADD key [NX | XX] [CH] [INCR] score member [score member ...]
+pub fn zadd(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, None)?;
+    let (mut nx, mut xx, mut ch, mut incr) = (false, false, false, false);
+    let mut i = 2;
+    while i < args.len() {
+        match String::from_utf8_lossy(&args[i]).to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => ch = true,
+            "INCR" => incr = true,
+            _ => break,
+        }
+        i += 1;
+    }
+
+    let pairs = &args[i..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(syntax_error());
+    }
+    if nx && xx {
+        return Err(Value::error(
+            "ERR XX and NX options at the same time are not compatible",
+        ));
+    }
+    if incr && pairs.len() != 2 {
+        return Err(Value::error(
+            "ERR INCR option supports a single increment-element pair",
+        ));
+    }
+    let mut updates = Vec::with_capacity(pairs.len() / 2);
+    for pair in pairs.chunks(2) {
+        updates.push((parse_score(&pair[0])?, pair[1].clone()));
+    }
+
+    let zset = db.write::<SortedSet>(&args[1], true)?.expect("created");
+    let mut added = 0;
+    let mut changed = 0;
+    let mut incr_result = None;
+    for (score, member) in updates {
+        let current = zset.score(&member);
+        if (nx && current.is_some()) || (xx && current.is_none()) {
+            continue;
+        }
+        let score = match (incr, current) {
+            (true, Some(current)) => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            db.remove_if_empty(&args[1]);
+            return Err(Value::error("ERR resulting score is not a number (NaN)"));
+        }
+        if current != Some(score) {
+            changed += 1;
+        }
+        if zset.insert(member, score) {
+            added += 1;
+        }
+        incr_result = Some(score);
+    }
+    if changed > 0 {
+        db.touch(&args[1]);
+    }
+    db.remove_if_empty(&args[1]);
+
+    if incr {
+        return Ok(match incr_result {
+            Some(score) => Value::bulk(format_score(score)),
+            None => Value::Null,
+        });
+    }
+    Ok(Value::Integer(if ch { changed } else { added }))
+}
+
+/// ZINCRBY key increment member
+pub fn zincrby(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, Some(4))?;
+    let increment = parse_score(&args[2])?;
+    let zset = db.write::<SortedSet>(&args[1], true)?.expect("created");
+    let score = zset.score(&args[3]).unwrap_or(0.0) + increment;
+    if score.is_nan() {
+        db.remove_if_empty(&args[1]);
+        return Err(Value::error("ERR resulting score is not a number (NaN)"));
+    }
+    zset.insert(args[3].clone(), score);
+    db.touch(&args[1]);
+    Ok(Value::bulk(format_score(score)))
+}
+
+/// ZRANGE key start stop [REV] [WITHSCORES]
+pub fn zrange(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, None)?;
+    let start = parse_int(&args[2])?;
+    let stop = parse_int(&args[3])?;
+    let (mut rev, mut scores) = (false, false);
+    for option in &args[4..] {
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            "REV" => rev = true,
+            "WITHSCORES" => scores = true,
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    let zset = match db.read::<SortedSet>(&args[1])? {
+        Some(zset) => zset,
+        None => return Ok(Value::Array(vec![])),
+    };
+    let (from, to) = match normalize_range(start, stop, zset.len()) {
+        Some(range) => range,
+        None => return Ok(Value::Array(vec![])),
+    };
+    Ok(if rev {
+        with_scores(zset.iter().rev().skip(from).take(to - from), scores)
+    } else {
+        with_scores(zset.iter().skip(from).take(to - from), scores)
+    })
+}
+
+/// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+pub fn zrangebyscore(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 4, None)?;
+    let min = parse_bound(&args[2])?;
+    let max = parse_bound(&args[3])?;
+    let mut scores = false;
+    let mut offset = 0;
+    let mut count = usize::MAX;
+
+    let mut i = 4;
+    while i < args.len() {
+        match String::from_utf8_lossy(&args[i]).to_uppercase().as_str() {
+            "WITHSCORES" => scores = true,
+            "LIMIT" if i + 2 < args.len() => {
+                let requested_offset = parse_int(&args[i + 1])?;
+                let requested_count = parse_int(&args[i + 2])?;
+                if requested_offset < 0 {
+                    return Ok(Value::Array(vec![]));
+                }
+                offset = requested_offset as usize;
+                // A negative count means "all remaining elements".
+                count = usize::try_from(requested_count).unwrap_or(usize::MAX);
+                i += 2;
+            }
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    Ok(match db.read::<SortedSet>(&args[1])? {
+        Some(zset) => with_scores(
+            zset.range_by_score(min, max).skip(offset).take(count),
+            scores,
+        ),
+        None => Value::Array(vec![]),
+    })
+}
+
+pub fn zrank(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    Ok(
+        match db
+            .read::<SortedSet>(&args[1])?
+            .and_then(|zset| zset.rank(&args[2]))
+        {
+            Some(rank) => Value::Integer(rank as i64),
+            None => Value::Null,
+        },
+    )
+}
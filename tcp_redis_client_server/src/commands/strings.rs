@@ -0,0 +1,105 @@
+use super::{Reply, check_arity, parse_int, syntax_error, wrong_type};
+use crate::db::{Db, Object, now_ms};
+use crate::resp::Value;
+
+pub fn get(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 2, Some(2))?;
+    Ok(match db.get(&args[1]) {
+        Some(Object::String(value)) => Value::bulk(value.clone()),
+        Some(_) => return Err(wrong_type()),
+        None => Value::Null,
+    })
+}
+
+/// SET key value [NX | XX] [EX seconds | PX milliseconds | PXAT unix-ms | KEEPTTL]
+pub fn set(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, None)?;
+    let mut nx = false;
+    let mut xx = false;
+    let mut keep_ttl = false;
+    let mut expire_at = None;
+
+    let mut i = 3;
+    while i < args.len() {
+        let option = String::from_utf8_lossy(&args[i]).to_uppercase();
+        match option.as_str() {
+            "NX" if !xx => nx = true,
+            "XX" if !nx => xx = true,
+            "KEEPTTL" if expire_at.is_none() => keep_ttl = true,
+            "EX" | "PX" | "PXAT" if expire_at.is_none() && !keep_ttl => {
+                let amount = parse_int(args.get(i + 1).ok_or_else(syntax_error)?)?;
+                if amount <= 0 {
+                    return Err(Value::error("ERR invalid expire time in 'set' command"));
+                }
+                expire_at = Some(match option.as_str() {
+                    "EX" => now_ms().saturating_add((amount as u64).saturating_mul(1000)),
+                    "PX" => now_ms().saturating_add(amount as u64),
+                    _ => amount as u64,
+                });
+                i += 1;
+            }
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    let key = &args[1];
+    let exists = db.get(key).is_some();
+    if (nx && exists) || (xx && !exists) {
+        return Ok(Value::Null);
+    }
+    db.set(key.clone(), Object::String(args[2].clone()), keep_ttl);
+    if let Some(at) = expire_at {
+        super::expire_key(db, key, at);
+    }
+    Ok(Value::ok())
+}
+
+/// INCR/DECR/INCRBY/DECRBY; `sign` is -1 for the DECR variants. The TTL is
+/// kept, as the key is modified in place.
+pub fn incr_by(db: &mut Db, name: &str, args: &[Vec<u8>], sign: i64) -> Reply {
+    let by = match name {
+        "INCR" | "DECR" => {
+            check_arity(name, args, 2, Some(2))?;
+            1
+        }
+        _ => {
+            check_arity(name, args, 3, Some(3))?;
+            parse_int(&args[2])?
+        }
+    };
+    let current = match db.get(&args[1]) {
+        Some(Object::String(value)) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| Value::error("ERR value is not an integer or out of range"))?,
+        Some(_) => return Err(wrong_type()),
+        None => 0,
+    };
+    let updated = by
+        .checked_mul(sign)
+        .and_then(|by| current.checked_add(by))
+        .ok_or_else(|| Value::error("ERR increment or decrement would overflow"))?;
+
+    let value = Object::String(updated.to_string().into_bytes());
+    db.set(args[1].clone(), value, true);
+    Ok(Value::Integer(updated))
+}
+
+/// APPEND key value; creates the key if it is missing.
+pub fn append(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    let value = db.write::<Vec<u8>>(&args[1], true)?.expect("created");
+    value.extend_from_slice(&args[2]);
+    let len = value.len();
+    db.touch(&args[1]);
+    Ok(Value::Integer(len as i64))
+}
+
+/// GETSET key value: sets the value (clearing any TTL) and returns the old one.
+pub fn getset(db: &mut Db, name: &str, args: &[Vec<u8>]) -> Reply {
+    check_arity(name, args, 3, Some(3))?;
+    let old = get(db, name, &args[..2])?;
+    db.set(args[1].clone(), Object::String(args[2].clone()), false);
+    Ok(old)
+}
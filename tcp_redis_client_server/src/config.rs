@@ -0,0 +1,137 @@
+use std::env;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+/// What to do with a subscriber whose output buffer is full.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    Disconnect,
+    Drop,
+}
+
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub max_clients: usize,
+    pub dir: PathBuf,
+    pub db_filename: String,
+    pub append_only: bool,
+    pub append_filename: String,
+    pub append_fsync: FsyncPolicy,
+    pub aof_repair: bool,
+    pub pubsub_buffer_limit: usize,
+    pub pubsub_overflow: OverflowPolicy,
+    pub replica_of: Option<(String, u16)>,
+    // In microseconds; negative disables the slow log, 0 logs everything.
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+}
+
+pub const USAGE: &str = "Usage: tcp_redis [--bind <ip>] [--port <port>] [--maxclients <n>]
+                 [--dir <path>] [--dbfilename <name>]
+                 [--appendonly yes|no] [--appendfilename <name>]
+                 [--appendfsync always|everysec|no] [--aof-repair]
+                 [--pubsub-buffer-limit <bytes>] [--pubsub-overflow disconnect|drop]
+                 [--replicaof <host>:<port>]
+                 [--slowlog-log-slower-than <micros>] [--slowlog-max-len <n>]";
+
+fn parse<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
+
+fn parse_bool(option: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!(
+            "Invalid value for {}: {} (expected yes|no)",
+            option, value
+        )),
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6789,
+            max_clients: 10000,
+            dir: PathBuf::from("."),
+            db_filename: "dump.rdb".to_string(),
+            append_only: false,
+            append_filename: "appendonly.aof".to_string(),
+            append_fsync: FsyncPolicy::EverySec,
+            aof_repair: false,
+            pubsub_buffer_limit: 32 * 1024 * 1024,
+            pubsub_overflow: OverflowPolicy::Disconnect,
+            replica_of: None,
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(option) = args.next() {
+            if option == "--aof-repair" {
+                config.aof_repair = true;
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", option))?;
+            match option.as_str() {
+                "--bind" => config.bind = value,
+                "--port" => config.port = parse(&option, &value)?,
+                "--maxclients" => config.max_clients = parse(&option, &value)?,
+                "--dir" => config.dir = PathBuf::from(value),
+                "--dbfilename" => config.db_filename = value,
+                "--appendonly" => config.append_only = parse_bool(&option, &value)?,
+                "--appendfilename" => config.append_filename = value,
+                "--appendfsync" => {
+                    config.append_fsync = match value.as_str() {
+                        "always" => FsyncPolicy::Always,
+                        "everysec" => FsyncPolicy::EverySec,
+                        "no" => FsyncPolicy::No,
+                        _ => return Err(format!("Invalid value for {}: {}", option, value)),
+                    }
+                }
+                "--pubsub-buffer-limit" => config.pubsub_buffer_limit = parse(&option, &value)?,
+                "--pubsub-overflow" => {
+                    config.pubsub_overflow = match value.as_str() {
+                        "disconnect" => OverflowPolicy::Disconnect,
+                        "drop" => OverflowPolicy::Drop,
+                        _ => return Err(format!("Invalid value for {}: {}", option, value)),
+                    }
+                }
+                "--replicaof" => {
+                    let (host, port) = value
+                        .rsplit_once(':')
+                        .ok_or_else(|| format!("Invalid value for {}: {}", option, value))?;
+                    config.replica_of = Some((host.to_string(), parse(&option, port)?));
+                }
+                "--slowlog-log-slower-than" => {
+                    config.slowlog_log_slower_than = parse(&option, &value)?
+                }
+                "--slowlog-max-len" => config.slowlog_max_len = parse(&option, &value)?,
+                _ => return Err(format!("Unknown option: {}", option)),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.db_filename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.append_filename)
+    }
+}
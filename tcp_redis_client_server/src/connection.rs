@@ -0,0 +1,147 @@
+use crate::resp::Value;
+use std::io::{self, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// What CLIENT LIST reports about a connection; kept up to date by the
+/// thread serving it.
+#[derive(Clone)]
+pub struct ClientInfo {
+    pub name: String,
+    pub command: String,
+    pub last_active: Instant,
+    pub subscriptions: usize,
+    pub patterns: usize,
+    pub multi: Option<usize>,
+    pub monitor: bool,
+    pub replica: bool,
+}
+
+/// The shared half of a client connection. Replies and pushed messages are
+/// queued on `outbox` and written by a dedicated writer thread, so a
+/// publisher never blocks on a subscriber's socket.
+pub struct Connection {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub created: Instant,
+    pub info: Mutex<ClientInfo>,
+    stream: TcpStream,
+    outbox: Sender<Vec<u8>>,
+    // Bytes queued on `outbox` that the writer hasn't written yet.
+    queued: Arc<AtomicUsize>,
+    protocol: AtomicU8,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    /// Wraps `stream` and starts its writer thread. The thread exits once
+    /// every handle to the connection has been dropped and the queue drained.
+    pub fn open(id: u64, stream: &TcpStream) -> io::Result<(Arc<Connection>, JoinHandle<()>)> {
+        let (outbox, queue) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let writer_stream = stream.try_clone()?;
+        let writer_queued = Arc::clone(&queued);
+        let writer = thread::spawn(move || {
+            if let Err(e) = write_queue(writer_stream, queue, &writer_queued) {
+                eprintln!("Error writing to client: {}", e);
+            }
+        });
+
+        let connection = Connection {
+            id,
+            addr: stream.peer_addr()?,
+            created: Instant::now(),
+            info: Mutex::new(ClientInfo {
+                name: String::new(),
+                command: "NULL".to_string(),
+                last_active: Instant::now(),
+                subscriptions: 0,
+                patterns: 0,
+                multi: None,
+                monitor: false,
+                replica: false,
+            }),
+            stream: stream.try_clone()?,
+            outbox,
+            queued,
+            protocol: AtomicU8::new(2),
+            closed: AtomicBool::new(false),
+        };
+        Ok((Arc::new(connection), writer))
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn set_protocol(&self, protocol: u8) {
+        self.protocol.store(protocol, Ordering::Relaxed);
+    }
+
+    /// Bytes waiting to be written to the socket.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Queues a reply to a command the client sent. Replies are never limited.
+    pub fn send(&self, value: &Value) {
+        self.send_bytes(value.encode(self.protocol()));
+    }
+
+    /// Queues a pushed message unless that would take the output buffer past
+    /// `limit` bytes. Returns false if the message was not queued.
+    pub fn push(&self, value: &Value, limit: usize) -> bool {
+        if self.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let data = value.encode(self.protocol());
+        if self.queued.load(Ordering::Relaxed) + data.len() > limit {
+            return false;
+        }
+        self.send_bytes(data);
+        true
+    }
+
+    /// Queues data that is already encoded, such as the replication stream.
+    pub fn send_bytes(&self, data: Vec<u8>) {
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        // The writer only goes away when the socket is dead, in which case
+        // the reader is about to notice as well.
+        let _ = self.outbox.send(data);
+    }
+
+    /// Stops reading new commands; queued replies are still written.
+    pub fn shutdown_read(&self) {
+        let _ = self.stream.shutdown(Shutdown::Read);
+    }
+
+    /// Drops the connection, including anything still queued for it.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn write_queue(
+    stream: TcpStream,
+    queue: Receiver<Vec<u8>>,
+    queued: &AtomicUsize,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream);
+    while let Ok(data) = queue.recv() {
+        writer.write_all(&data)?;
+        queued.fetch_sub(data.len(), Ordering::Relaxed);
+        // Batch whatever else is already waiting (pipelined replies, bursts
+        // of messages) into a single flush.
+        while let Ok(data) = queue.try_recv() {
+            writer.write_all(&data)?;
+            queued.fetch_sub(data.len(), Ordering::Relaxed);
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,429 @@
+use crate::zset::SortedSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_BUDGET_MS: u128 = 25;
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone)]
+pub enum Object {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    ZSet(SortedSet),
+}
+
+impl Object {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::String(_) => "string",
+            Object::List(_) => "list",
+            Object::Hash(_) => "hash",
+            Object::Set(_) => "set",
+            Object::ZSet(_) => "zset",
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Object::String(_) => false,
+            Object::List(list) => list.is_empty(),
+            Object::Hash(hash) => hash.is_empty(),
+            Object::Set(set) => set.is_empty(),
+            Object::ZSet(zset) => zset.is_empty(),
+        }
+    }
+
+    /// Rough size of the value in bytes, for INFO memory.
+    fn memory_usage(&self) -> usize {
+        const OVERHEAD: usize = 16;
+        match self {
+            Object::String(s) => s.len(),
+            Object::List(list) => list.iter().map(|item| item.len() + OVERHEAD).sum(),
+            Object::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len() + 2 * OVERHEAD)
+                .sum(),
+            Object::Set(set) => set.iter().map(|member| member.len() + OVERHEAD).sum(),
+            Object::ZSet(zset) => zset
+                .iter()
+                .map(|(member, _)| 2 * (member.len() + OVERHEAD) + 8)
+                .sum(),
+        }
+    }
+}
+
+/// The value types `Db::read` and `Db::write` hand out.
+pub trait ValueType: Default {
+    fn of(object: &Object) -> Option<&Self>;
+    fn of_mut(object: &mut Object) -> Option<&mut Self>;
+    fn into_object(self) -> Object;
+}
+
+macro_rules! value_type {
+    ($type:ty, $variant:ident) => {
+        impl ValueType for $type {
+            fn of(object: &Object) -> Option<&Self> {
+                match object {
+                    Object::$variant(value) => Some(value),
+                    _ => None,
+                }
+            }
+
+            fn of_mut(object: &mut Object) -> Option<&mut Self> {
+                match object {
+                    Object::$variant(value) => Some(value),
+                    _ => None,
+                }
+            }
+
+            fn into_object(self) -> Object {
+                Object::$variant(self)
+            }
+        }
+    };
+}
+
+value_type!(Vec<u8>, String);
+value_type!(VecDeque<Vec<u8>>, List);
+value_type!(HashMap<Vec<u8>, Vec<u8>>, Hash);
+value_type!(HashSet<Vec<u8>>, Set);
+value_type!(SortedSet, ZSet);
+
+/// A key holds a different type than the command works on.
+pub struct WrongType;
+
+struct Entry {
+    value: Object,
+    // Absolute unix time in milliseconds and the key's slot in `Db::volatile`.
+    expiry: Option<(u64, usize)>,
+}
+
+/// A key some client has WATCHed. `version` is the `dirty` counter at the
+/// key's last modification; it outlives the key itself, so deleting and
+/// recreating a watched key still counts as a change.
+struct WatchedKey {
+    watchers: usize,
+    version: u64,
+}
+
+/// The keyspace. Keys with a TTL are also tracked in `volatile` so the
+/// active expiry cycle can sample them at random in O(1), like Redis does.
+pub struct Db {
+    data: HashMap<Vec<u8>, Entry>,
+    volatile: Vec<Vec<u8>>,
+    watched: HashMap<Vec<u8>, WatchedKey>,
+    rng: u64,
+    dirty: u64,
+    expired: u64,
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Db {
+            data: HashMap::new(),
+            volatile: Vec::new(),
+            watched: HashMap::new(),
+            rng: now_ms() | 1,
+            dirty: 0,
+            expired: 0,
+        }
+    }
+
+    /// Number of modifications since startup; callers compare it before and
+    /// after a command to know whether the command changed the dataset.
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Number of keys with a TTL.
+    pub fn expires(&self) -> usize {
+        self.volatile.len()
+    }
+
+    /// Keys removed because their TTL ran out, lazily or actively.
+    pub fn expired_keys(&self) -> u64 {
+        self.expired
+    }
+
+    /// Estimated memory used by the keyspace. There is no allocator to ask,
+    /// so this adds up key and value sizes plus a fixed per-entry overhead.
+    pub fn memory_usage(&self) -> usize {
+        const ENTRY_OVERHEAD: usize = 48;
+        self.data
+            .iter()
+            .map(|(key, entry)| key.len() + entry.value.memory_usage() + ENTRY_OVERHEAD)
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Object, Option<u64>)> {
+        self.data
+            .iter()
+            .map(|(key, entry)| (key.as_slice(), &entry.value, entry.expiry.map(|(at, _)| at)))
+    }
+
+    /// Point-in-time copy of the dataset for background saves.
+    pub fn snapshot(&self) -> Vec<(Vec<u8>, Object, Option<u64>)> {
+        self.iter()
+            .map(|(key, value, expiry)| (key.to_vec(), value.clone(), expiry))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty += self.data.len() as u64;
+        for (key, watched) in self.watched.iter_mut() {
+            if self.data.contains_key(key) {
+                watched.version = self.dirty;
+            }
+        }
+        self.data.clear();
+        self.volatile.clear();
+    }
+
+    /// Counts a modification of `key`. Commands call it once they have
+    /// really changed a value they got from `write`.
+    pub fn touch(&mut self, key: &[u8]) {
+        self.dirty += 1;
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version = self.dirty;
+        }
+    }
+
+    /// Starts tracking `key` for WATCH and returns its current version.
+    pub fn watch(&mut self, key: &[u8]) -> u64 {
+        self.expire_if_needed(key);
+        let dirty = self.dirty;
+        let watched = self.watched.entry(key.to_vec()).or_insert(WatchedKey {
+            watchers: 0,
+            version: dirty,
+        });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Current version of a watched key. A key whose TTL ran out since it
+    /// was watched counts as modified, even if nobody looked it up.
+    pub fn version(&mut self, key: &[u8]) -> u64 {
+        self.expire_if_needed(key);
+        self.watched.get(key).map_or(0, |watched| watched.version)
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> bool {
+        matches!(self.data.get(key), Some(Entry { expiry: Some((at, _)), .. }) if *at <= now)
+    }
+
+    /// Lazy expiry: every lookup first drops the key if its TTL has passed.
+    fn expire_if_needed(&mut self, key: &[u8]) {
+        if self.is_expired(key, now_ms()) {
+            self.remove(key);
+            self.expired += 1;
+        }
+    }
+
+    fn untrack(&mut self, slot: usize) {
+        self.volatile.swap_remove(slot);
+        if let Some(moved) = self.volatile.get(slot)
+            && let Some(Entry {
+                expiry: Some((_, moved_slot)),
+                ..
+            }) = self.data.get_mut(moved)
+        {
+            *moved_slot = slot;
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<&Object> {
+        self.expire_if_needed(key);
+        self.data.get(key).map(|entry| &entry.value)
+    }
+
+    /// Looks up `key` as a `T`.
+    pub fn read<T: ValueType>(&mut self, key: &[u8]) -> Result<Option<&T>, WrongType> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(object) => T::of(object).map(Some).ok_or(WrongType),
+        }
+    }
+
+    /// Mutable access to `key` as a `T`, created empty if it is missing and
+    /// `create` is set. Nothing counts as changed until the caller calls
+    /// `touch`, and aggregates left empty must be cleaned up with
+    /// `remove_if_empty` afterwards.
+    pub fn write<T: ValueType>(
+        &mut self,
+        key: &[u8],
+        create: bool,
+    ) -> Result<Option<&mut T>, WrongType> {
+        self.expire_if_needed(key);
+        if !self.data.contains_key(key) {
+            if !create {
+                return Ok(None);
+            }
+            let entry = Entry {
+                value: T::default().into_object(),
+                expiry: None,
+            };
+            self.data.insert(key.to_vec(), entry);
+        }
+        let entry = self.data.get_mut(key).expect("key exists");
+        T::of_mut(&mut entry.value).map(Some).ok_or(WrongType)
+    }
+
+    /// Redis never keeps empty lists, hashes, sets or sorted sets around.
+    /// Whatever emptied it was already counted, and one created empty by
+    /// `write` never changed anything.
+    pub fn remove_if_empty(&mut self, key: &[u8]) {
+        if self
+            .data
+            .get(key)
+            .is_some_and(|entry| entry.value.is_empty())
+        {
+            self.drop_entry(key);
+        }
+    }
+
+    /// Stores `value`, clearing any TTL unless `keep_ttl` is set.
+    pub fn set(&mut self, key: Vec<u8>, value: Object, keep_ttl: bool) {
+        if keep_ttl {
+            self.expire_if_needed(&key);
+        } else {
+            self.persist(&key);
+        }
+        self.touch(&key);
+        match self.data.get_mut(&key) {
+            Some(entry) => entry.value = value,
+            None => {
+                self.data.insert(
+                    key,
+                    Entry {
+                        value,
+                        expiry: None,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        let removed = self.drop_entry(key);
+        if removed {
+            self.touch(key);
+        }
+        removed
+    }
+
+    fn drop_entry(&mut self, key: &[u8]) -> bool {
+        match self.data.remove(key) {
+            Some(entry) => {
+                if let Some((_, slot)) = entry.expiry {
+                    self.untrack(slot);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `None` for a missing key, `Some(None)` for a key without TTL.
+    pub fn expiry(&mut self, key: &[u8]) -> Option<Option<u64>> {
+        self.expire_if_needed(key);
+        self.data
+            .get(key)
+            .map(|entry| entry.expiry.map(|(at, _)| at))
+    }
+
+    pub fn set_expiry(&mut self, key: &[u8], at: u64) -> bool {
+        self.expire_if_needed(key);
+        let slot = self.volatile.len();
+        match self.data.get_mut(key) {
+            Some(Entry {
+                expiry: Some((current, _)),
+                ..
+            }) => *current = at,
+            Some(entry) => {
+                entry.expiry = Some((at, slot));
+                self.volatile.push(key.to_vec());
+            }
+            None => return false,
+        }
+        self.touch(key);
+        true
+    }
+
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        self.expire_if_needed(key);
+        let slot = match self.data.get_mut(key) {
+            Some(entry) => match entry.expiry.take() {
+                Some((_, slot)) => slot,
+                None => return false,
+            },
+            None => return false,
+        };
+        self.untrack(slot);
+        self.touch(key);
+        true
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64, good enough for picking sample keys.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Active expiry: samples random keys that have a TTL and deletes the
+    /// expired ones, repeating while more than a quarter of each sample was
+    /// expired and the time budget allows. Returns how many keys were removed.
+    pub fn active_expire_cycle(&mut self) -> usize {
+        let started = Instant::now();
+        let mut removed = 0;
+
+        loop {
+            let now = now_ms();
+            let samples = self.volatile.len().min(ACTIVE_EXPIRE_SAMPLE);
+            let mut expired = 0;
+            for _ in 0..samples {
+                if self.volatile.is_empty() {
+                    break;
+                }
+                let slot = (self.next_random() % self.volatile.len() as u64) as usize;
+                let key = self.volatile[slot].clone();
+                if self.is_expired(&key, now) {
+                    self.remove(&key);
+                    self.expired += 1;
+                    expired += 1;
+                }
+            }
+            removed += expired;
+
+            if samples == 0
+                || expired * 4 <= samples
+                || started.elapsed().as_millis() >= ACTIVE_EXPIRE_BUDGET_MS
+            {
+                return removed;
+            }
+        }
+    }
+}
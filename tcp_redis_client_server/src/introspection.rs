@@ -0,0 +1,486 @@
+use crate::config::OverflowPolicy;
+use crate::connection::Connection;
+use crate::db::now_ms;
+use crate::resp::Value;
+use crate::{BackgroundJob, Client, RedisServer};
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const INFO_SECTIONS: [&str; 7] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "keyspace",
+];
+
+/// Outcome of the last snapshot and AOF rewrite.
+pub struct SaveStatus {
+    pub time: u64,
+    // `Db::dirty` at the point the last snapshot was taken.
+    pub dirty: u64,
+    pub bgsave_ok: bool,
+    pub aof_rewrite_ok: bool,
+}
+
+/// Counters reported by INFO.
+pub struct Stats {
+    pub started: Instant,
+    pub connections_received: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub commands_processed: AtomicU64,
+    pub last_save: Mutex<SaveStatus>,
+}
+
+impl Stats {
+    pub fn new(dirty: u64) -> Self {
+        Stats {
+            started: Instant::now(),
+            connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            last_save: Mutex::new(SaveStatus {
+                time: now_ms() / 1000,
+                dirty,
+                bgsave_ok: true,
+                aof_rewrite_ok: true,
+            }),
+        }
+    }
+
+    pub fn saved(&self, dirty: u64, ok: bool) {
+        let mut last_save = self.last_save.lock().unwrap();
+        last_save.bgsave_ok = ok;
+        if ok {
+            last_save.time = now_ms() / 1000;
+            last_save.dirty = dirty;
+        }
+    }
+}
+
+/// Commands that are not shown to MONITOR, like Redis' admin commands.
+fn is_admin(name: &str) -> bool {
+    matches!(
+        name,
+        "PSYNC"
+            | "SYNC"
+            | "REPLCONF"
+            | "MONITOR"
+            | "SLOWLOG"
+            | "SAVE"
+            | "BGSAVE"
+            | "BGREWRITEAOF"
+            | "REPLICAOF"
+            | "SLAVEOF"
+    )
+}
+
+/// Quotes an argument the way MONITOR prints it.
+fn repr(arg: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in arg {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => {
+                let _ = write!(quoted, "\\x{:02x}", byte);
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats a byte count like Redis' `*_human` INFO fields.
+fn human_bytes(bytes: usize) -> String {
+    let bytes = bytes as f64;
+    match bytes {
+        b if b < 1024.0 => format!("{}B", b),
+        b if b < 1024.0 * 1024.0 => format!("{:.2}K", b / 1024.0),
+        b if b < 1024.0 * 1024.0 * 1024.0 => format!("{:.2}M", b / (1024.0 * 1024.0)),
+        b => format!("{:.2}G", b / (1024.0 * 1024.0 * 1024.0)),
+    }
+}
+
+/// Resident set size of the process, where the OS tells us.
+fn resident_memory() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+            let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+            Some(kb * 1024)
+        })
+        .unwrap_or(0)
+}
+
+fn client_line(connection: &Connection) -> String {
+    let info = connection.info.lock().unwrap();
+    let mut flags = String::new();
+    if info.replica {
+        flags.push('S');
+    }
+    if info.monitor {
+        flags.push('O');
+    }
+    if info.subscriptions + info.patterns > 0 {
+        flags.push('P');
+    }
+    if info.multi.is_some() {
+        flags.push('x');
+    }
+    if flags.is_empty() {
+        flags.push('N');
+    }
+    format!(
+        "id={} addr={} name={} age={} idle={} flags={} db=0 sub={} psub={} multi={} omem={} resp={} cmd={}\n",
+        connection.id,
+        connection.addr,
+        info.name,
+        connection.created.elapsed().as_secs(),
+        info.last_active.elapsed().as_secs(),
+        flags,
+        info.subscriptions,
+        info.patterns,
+        info.multi.map_or(-1, |queued| queued as i64),
+        connection.queued_bytes(),
+        connection.protocol(),
+        info.command
+    )
+}
+
+impl RedisServer {
+    /// INFO [section ...]. Without arguments, or with `all`/`default`/
+    /// `everything`, every section is returned.
+    pub(crate) fn info(&self, args: &[Vec<u8>]) -> Value {
+        let requested: Vec<String> = args[1..]
+            .iter()
+            .map(|section| String::from_utf8_lossy(section).to_lowercase())
+            .collect();
+        let everything = requested.is_empty()
+            || requested
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+
+        let sections: Vec<String> = INFO_SECTIONS
+            .iter()
+            .filter(|section| everything || requested.iter().any(|r| r == *section))
+            .map(|section| self.info_section(section))
+            .filter(|text| !text.is_empty())
+            .collect();
+        Value::bulk(sections.join("\r\n"))
+    }
+
+    fn info_section(&self, section: &str) -> String {
+        let mut info = String::new();
+        match section {
+            "server" => {
+                let uptime = self.stats.started.elapsed().as_secs();
+                let _ = write!(
+                    info,
+                    "# Server\r\nredis_version:{}\r\nredis_mode:standalone\r\nos:{} {}\r\n\
+                     process_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{}\r\nuptime_in_days:{}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    std::env::consts::OS,
+                    std::env::consts::ARCH,
+                    std::process::id(),
+                    self.config.port,
+                    uptime,
+                    uptime / 86400
+                );
+            }
+            "clients" => {
+                let clients = self.clients.lock().unwrap();
+                let max_output = clients
+                    .values()
+                    .map(|connection| connection.queued_bytes())
+                    .max()
+                    .unwrap_or(0);
+                let _ = write!(
+                    info,
+                    "# Clients\r\nconnected_clients:{}\r\nmaxclients:{}\r\n\
+                     client_recent_max_output_buffer:{}\r\n",
+                    clients.len(),
+                    self.config.max_clients,
+                    max_output
+                );
+            }
+            "memory" => {
+                let used = self.db.lock().unwrap().memory_usage();
+                let rss = resident_memory();
+                let _ = write!(
+                    info,
+                    "# Memory\r\nused_memory:{}\r\nused_memory_human:{}\r\n\
+                     used_memory_rss:{}\r\nused_memory_rss_human:{}\r\n",
+                    used,
+                    human_bytes(used),
+                    rss,
+                    human_bytes(rss)
+                );
+            }
+            "persistence" => {
+                let dirty = self.db.lock().unwrap().dirty();
+                let job = *self.background_job.lock().unwrap();
+                let last_save = self.stats.last_save.lock().unwrap();
+                let status = |ok| if ok { "ok" } else { "err" };
+                let _ = write!(
+                    info,
+                    "# Persistence\r\nloading:0\r\nrdb_changes_since_last_save:{}\r\n\
+                     rdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\n\
+                     rdb_last_bgsave_status:{}\r\naof_enabled:{}\r\n\
+                     aof_rewrite_in_progress:{}\r\naof_last_bgrewrite_status:{}\r\n",
+                    dirty.saturating_sub(last_save.dirty),
+                    (job == Some(BackgroundJob::Save)) as u8,
+                    last_save.time,
+                    status(last_save.bgsave_ok),
+                    self.config.append_only as u8,
+                    (job == Some(BackgroundJob::AofRewrite)) as u8,
+                    status(last_save.aof_rewrite_ok)
+                );
+                drop(last_save);
+                if self.config.append_only {
+                    let _ = write!(info, "aof_current_size:{}\r\n", self.aof_len());
+                }
+            }
+            "stats" => {
+                let expired = self.db.lock().unwrap().expired_keys();
+                let (channels, patterns) = {
+                    let pubsub = self.pubsub.lock().unwrap();
+                    (pubsub.channels(None).len(), pubsub.pattern_count())
+                };
+                let _ = write!(
+                    info,
+                    "# Stats\r\ntotal_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+                     rejected_connections:{}\r\nexpired_keys:{}\r\n\
+                     pubsub_channels:{}\r\npubsub_patterns:{}\r\n",
+                    self.stats.connections_received.load(Ordering::Relaxed),
+                    self.stats.commands_processed.load(Ordering::Relaxed),
+                    self.stats.rejected_connections.load(Ordering::Relaxed),
+                    expired,
+                    channels,
+                    patterns
+                );
+            }
+            "replication" => info = self.replication.lock().unwrap().info(),
+            "keyspace" => {
+                let db = self.db.lock().unwrap();
+                info.push_str("# Keyspace\r\n");
+                if db.len() > 0 {
+                    let _ = write!(info, "db0:keys={},expires={}\r\n", db.len(), db.expires());
+                }
+            }
+            _ => {}
+        }
+        info
+    }
+
+    /// MONITOR: from now on the connection receives every command the
+    /// server processes.
+    pub(crate) fn monitor(&self, client: &Client) {
+        client.connection.info.lock().unwrap().monitor = true;
+        client.connection.send(&Value::ok());
+        self.monitors
+            .lock()
+            .unwrap()
+            .insert(client.connection.id, Arc::clone(&client.connection));
+    }
+
+    pub(crate) fn feed_monitors(&self, client: &Client, name: &str, args: &[Vec<u8>]) {
+        let monitors = self.monitors.lock().unwrap();
+        if monitors.is_empty() || is_admin(name) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!(
+            "{}.{:06} [0 {}]",
+            now.as_secs(),
+            now.subsec_micros(),
+            client.connection.addr
+        );
+        for arg in args {
+            line.push(' ');
+            line.push_str(&repr(arg));
+        }
+
+        let line = Value::Simple(line);
+        for monitor in monitors.values() {
+            if !monitor.push(&line, self.config.pubsub_buffer_limit)
+                && self.config.pubsub_overflow == OverflowPolicy::Disconnect
+            {
+                eprintln!(
+                    "Disconnecting client {}: monitor output buffer limit reached",
+                    monitor.id
+                );
+                monitor.close();
+            }
+        }
+    }
+
+    /// Adds the command to the slow log if it took long enough.
+    pub(crate) fn log_if_slow(&self, client: &Client, args: &[Vec<u8>], duration: Duration) {
+        let threshold = self.config.slowlog_log_slower_than;
+        if threshold < 0 || duration.as_micros() < threshold as u128 {
+            return;
+        }
+        let name = client.connection.info.lock().unwrap().name.clone();
+        self.slowlog.lock().unwrap().record(
+            args,
+            duration,
+            client.connection.addr.to_string(),
+            name,
+        );
+    }
+
+    /// SLOWLOG GET [count] | LEN | RESET
+    pub(crate) fn slowlog_command(&self, args: &[Vec<u8>]) -> Value {
+        let subcommand = match args.get(1) {
+            Some(subcommand) => String::from_utf8_lossy(subcommand).to_uppercase(),
+            None => return Value::error("ERR wrong number of arguments for 'slowlog' command"),
+        };
+        let mut slowlog = self.slowlog.lock().unwrap();
+        match subcommand.as_str() {
+            "GET" if args.len() <= 3 => {
+                let count = match args.get(2) {
+                    None => 10,
+                    Some(count) => match std::str::from_utf8(count)
+                        .ok()
+                        .and_then(|c| c.parse::<i64>().ok())
+                    {
+                        Some(-1) => usize::MAX,
+                        Some(count) if count >= 0 => count as usize,
+                        _ => {
+                            return Value::error("ERR count should be greater than or equal to -1");
+                        }
+                    },
+                };
+                slowlog.get(count)
+            }
+            "LEN" if args.len() == 2 => Value::Integer(slowlog.len() as i64),
+            "RESET" if args.len() == 2 => {
+                slowlog.reset();
+                Value::ok()
+            }
+            _ => Value::error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'",
+                subcommand.to_lowercase()
+            )),
+        }
+    }
+
+    /// CLIENT LIST | INFO | ID | GETNAME | SETNAME <name> | KILL ...
+    pub(crate) fn client_command(&self, client: &Client, args: &[Vec<u8>]) -> Value {
+        let subcommand = match args.get(1) {
+            Some(subcommand) => String::from_utf8_lossy(subcommand).to_uppercase(),
+            None => return Value::error("ERR wrong number of arguments for 'client' command"),
+        };
+        let connection = &client.connection;
+        match subcommand.as_str() {
+            "LIST" if args.len() == 2 => {
+                let clients = self.clients.lock().unwrap();
+                let mut connections: Vec<_> = clients.values().collect();
+                connections.sort_by_key(|connection| connection.id);
+                Value::bulk(
+                    connections
+                        .into_iter()
+                        .map(|connection| client_line(connection))
+                        .collect::<String>(),
+                )
+            }
+            "INFO" if args.len() == 2 => Value::bulk(client_line(connection)),
+            "ID" if args.len() == 2 => Value::Integer(connection.id as i64),
+            "GETNAME" if args.len() == 2 => {
+                let name = connection.info.lock().unwrap().name.clone();
+                if name.is_empty() {
+                    Value::Null
+                } else {
+                    Value::bulk(name)
+                }
+            }
+            "SETNAME" if args.len() == 3 => {
+                if args[2].iter().any(|&byte| !(b'!'..=b'~').contains(&byte)) {
+                    return Value::error(
+                        "ERR Client names cannot contain spaces, newlines or special characters.",
+                    );
+                }
+                connection.info.lock().unwrap().name = String::from_utf8_lossy(&args[2]).into();
+                Value::ok()
+            }
+            "KILL" if args.len() == 3 => {
+                let addr = String::from_utf8_lossy(&args[2]);
+                let clients = self.clients.lock().unwrap();
+                match clients
+                    .values()
+                    .find(|other| other.addr.to_string() == addr)
+                {
+                    Some(other) => {
+                        other.close();
+                        Value::ok()
+                    }
+                    None => Value::error("ERR No such client"),
+                }
+            }
+            "KILL" if args.len() >= 4 && args.len().is_multiple_of(2) => {
+                self.kill_clients(client, args)
+            }
+            _ => Value::error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'",
+                subcommand.to_lowercase()
+            )),
+        }
+    }
+
+    /// CLIENT KILL [ID <id>] [ADDR <ip:port>] [SKIPME yes|no]: closes every
+    /// client matching all the filters and returns how many there were.
+    fn kill_clients(&self, client: &Client, args: &[Vec<u8>]) -> Value {
+        let mut id = None;
+        let mut addr = None;
+        let mut skip_me = true;
+        for pair in args[2..].chunks(2) {
+            let value = String::from_utf8_lossy(&pair[1]);
+            match String::from_utf8_lossy(&pair[0]).to_uppercase().as_str() {
+                "ID" => match value.parse::<u64>() {
+                    Ok(value) => id = Some(value),
+                    Err(_) => {
+                        return Value::error("ERR client-id should be greater than 0");
+                    }
+                },
+                "ADDR" => addr = Some(value.into_owned()),
+                "SKIPME" => match value.to_lowercase().as_str() {
+                    "yes" => skip_me = true,
+                    "no" => skip_me = false,
+                    _ => return Value::error("ERR syntax error"),
+                },
+                _ => return Value::error("ERR syntax error"),
+            }
+        }
+
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for other in clients.values() {
+            if id.is_some_and(|id| other.id != id)
+                || addr
+                    .as_ref()
+                    .is_some_and(|addr| other.addr.to_string() != *addr)
+                || (skip_me && other.id == client.connection.id)
+            {
+                continue;
+            }
+            other.close();
+            killed += 1;
+        }
+        Value::Integer(killed)
+    }
+}
@@ -1,115 +1,837 @@
-use std::collections::HashMap;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+mod commands;
+mod config;
+mod connection;
+mod db;
+mod introspection;
+mod persistence;
+mod pubsub;
+mod replication;
+mod resp;
+mod slowlog;
+mod zset;
+
+use config::{Config, FsyncPolicy};
+use connection::Connection;
+use db::Db;
+use introspection::Stats;
+use persistence::Aof;
+use pubsub::PubSub;
+use replication::Replication;
+use resp::Value;
+use slowlog::SlowLog;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq)]
+enum BackgroundJob {
+    Save,
+    AofRewrite,
+}
 
 struct RedisServer {
-    listener: TcpListener,
-    store: HashMap<String, String>,
+    config: Config,
+    db: Mutex<Db>,
+    // Lock order: `db`, then `aof`, then `replication`, so commands reach
+    // the AOF and the replicas in the order they were applied.
+    aof: Mutex<Option<Aof>>,
+    replication: Mutex<Replication>,
+    background_job: Mutex<Option<BackgroundJob>>,
+    clients: Mutex<HashMap<u64, Arc<Connection>>>,
+    monitors: Mutex<HashMap<u64, Arc<Connection>>>,
+    pubsub: Mutex<PubSub>,
+    slowlog: Mutex<SlowLog>,
+    stats: Stats,
+    next_client_id: AtomicU64,
+    shutdown: AtomicBool,
 }
 
-impl RedisServer {
-    fn new() -> io::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:6789")?;
-        println!("Redis server running at: 127.0.0.1:6789");
-        Ok(RedisServer {
-            listener,
-            store: HashMap::new(),
-        })
+struct Client {
+    connection: Arc<Connection>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    // Commands queued since MULTI, and whether queueing one of them failed.
+    transaction: Option<Vec<Vec<Vec<u8>>>>,
+    transaction_failed: bool,
+    // WATCHed keys with their version at the time of the WATCH.
+    watched: Vec<(Vec<u8>, u64)>,
+    // Port a replica announced with REPLCONF listening-port.
+    listening_port: Option<u16>,
+}
+
+impl Client {
+    fn new(connection: Arc<Connection>) -> Self {
+        Client {
+            connection,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            transaction: None,
+            transaction_failed: false,
+            watched: Vec::new(),
+            listening_port: None,
+        }
     }
 
-    fn set(&mut self, key: String, value: String) -> String {
-        self.store.insert(key, value);
-        "OK".to_string()
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
     }
 
-    fn del(&mut self, key: String) -> String {
-        match self.store.remove(&key) {
-            Some(_) => "1".to_string(),
-            None => "0".to_string(),
-        }
+    /// A RESP2 connection with active subscriptions can only manage them;
+    /// RESP3 delivers messages as push types, so it may keep sending commands.
+    fn in_subscriber_mode(&self) -> bool {
+        self.connection.protocol() < 3 && self.subscriptions() > 0
     }
+}
 
-    fn get(&self, key: &str) -> String {
-        match self.store.get(key) {
-            Some(value) => value.clone(),
-            None => "(nil)".to_string(),
+impl RedisServer {
+    fn new(config: Config, db: Db, aof: Option<Aof>) -> Self {
+        let replication = Replication::new(config.replica_of.clone());
+        let slowlog = SlowLog::new(config.slowlog_max_len);
+        let stats = Stats::new(db.dirty());
+        RedisServer {
+            config,
+            db: Mutex::new(db),
+            aof: Mutex::new(aof),
+            replication: Mutex::new(replication),
+            background_job: Mutex::new(None),
+            clients: Mutex::new(HashMap::new()),
+            monitors: Mutex::new(HashMap::new()),
+            pubsub: Mutex::new(PubSub::default()),
+            slowlog: Mutex::new(slowlog),
+            stats,
+            next_client_id: AtomicU64::new(1),
+            shutdown: AtomicBool::new(false),
         }
     }
 
-    fn process(&mut self, line: String) -> String {
-        let args: Vec<&str> = line.trim().split_whitespace().collect();
+    fn is_replica(&self) -> bool {
+        self.replication.lock().unwrap().is_replica()
+    }
 
-        if args.is_empty() {
-            return "ERROR: Empty command".to_string();
+    fn hello(&self, client: &mut Client, args: &[Vec<u8>]) -> Value {
+        if let Some(version) = args.get(1) {
+            match std::str::from_utf8(version)
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                Some(version @ (2 | 3)) => client.connection.set_protocol(version),
+                Some(_) => return Value::error("NOPROTO unsupported protocol version"),
+                None => {
+                    return Value::error("ERR Protocol version is not an integer or out of range");
+                }
+            }
         }
 
-        match args[0].to_uppercase().as_str() {
-            "GET" => {
+        Value::Map(vec![
+            (Value::bulk("server"), Value::bulk("redis")),
+            (
+                Value::bulk("version"),
+                Value::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (
+                Value::bulk("proto"),
+                Value::Integer(client.connection.protocol() as i64),
+            ),
+            (
+                Value::bulk("id"),
+                Value::Integer(client.connection.id as i64),
+            ),
+            (Value::bulk("mode"), Value::bulk("standalone")),
+            (
+                Value::bulk("role"),
+                Value::bulk(if self.is_replica() {
+                    "replica"
+                } else {
+                    "master"
+                }),
+            ),
+            (Value::bulk("modules"), Value::Array(vec![])),
+        ])
+    }
+
+    fn process(self: &Arc<Self>, client: &mut Client, args: &[Vec<u8>]) -> Value {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let wrong_args = || {
+            Value::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            ))
+        };
+
+        match name.as_str() {
+            "PING" if client.in_subscriber_mode() => match args.len() {
+                1 | 2 => Value::Array(vec![
+                    Value::bulk("pong"),
+                    Value::bulk(args.get(1).cloned().unwrap_or_default()),
+                ]),
+                _ => wrong_args(),
+            },
+            "HELLO" => self.hello(client, args),
+            "COMMAND" => Value::Array(vec![]),
+            "SELECT" => {
                 if args.len() != 2 {
-                    return "ERROR: GET requires exactly 1 argument".to_string();
+                    return wrong_args();
+                }
+                if args[1] != b"0" {
+                    return Value::error("ERR DB index is out of range");
                 }
-                self.get(args[1])
+                Value::ok()
             }
-            "SET" => {
-                if args.len() < 3 {
-                    return "ERROR: SET requires at least 2 arguments".to_string();
+            "SAVE" => match self.save() {
+                Ok(()) => Value::ok(),
+                Err(e) => Value::error(format!("ERR {}", e)),
+            },
+            "BGSAVE" => self.bgsave(),
+            "BGREWRITEAOF" => self.bgrewriteaof(),
+            "INFO" => self.info(args),
+            "CLIENT" => self.client_command(client, args),
+            "SLOWLOG" => self.slowlog_command(args),
+            "REPLICAOF" | "SLAVEOF" => self.replicaof(args),
+            "PUBLISH" => self.publish(args),
+            "PUBSUB" => self.pubsub_info(args),
+            "MULTI" => {
+                if args.len() != 1 {
+                    return wrong_args();
                 }
-                let key = args[1].to_string();
-                let value = args[2..].join(" ");
-                self.set(key, value)
+                client.transaction = Some(Vec::new());
+                Value::ok()
             }
-            "DEL" => {
-                if args.len() != 2 {
-                    return "ERROR: DEL requires exactly 1 argument".to_string();
+            "EXEC" => Value::error("ERR EXEC without MULTI"),
+            "DISCARD" => Value::error("ERR DISCARD without MULTI"),
+            "WATCH" => {
+                if args.len() < 2 {
+                    return wrong_args();
+                }
+                let mut db = self.db.lock().unwrap();
+                for key in &args[1..] {
+                    if !client.watched.iter().any(|(watched, _)| watched == key) {
+                        let version = db.watch(key);
+                        client.watched.push((key.clone(), version));
+                    }
                 }
-                self.del(args[1].to_string())
+                Value::ok()
+            }
+            "UNWATCH" => {
+                self.unwatch_all(client);
+                Value::ok()
+            }
+            _ if commands::is_write(args) && self.is_replica() => read_only(),
+            _ => self.call(args),
+        }
+    }
+
+    fn publish(&self, args: &[Vec<u8>]) -> Value {
+        if args.len() != 3 {
+            return Value::error("ERR wrong number of arguments for 'publish' command");
+        }
+        let receivers = self.pubsub.lock().unwrap().publish(
+            &args[1],
+            &args[2],
+            self.config.pubsub_buffer_limit,
+            self.config.pubsub_overflow,
+        );
+        Value::Integer(receivers as i64)
+    }
+
+    /// Handles a command sent between MULTI and EXEC: keyspace commands and
+    /// PUBLISH are queued, anything else is refused and dooms the transaction.
+    fn queue(&self, client: &mut Client, name: &str, args: &[Vec<u8>]) -> Value {
+        match name {
+            "EXEC" => self.exec(client),
+            "DISCARD" => {
+                client.transaction = None;
+                client.transaction_failed = false;
+                self.unwatch_all(client);
+                Value::ok()
+            }
+            "MULTI" => Value::error("ERR MULTI calls can not be nested"),
+            "WATCH" => Value::error("ERR WATCH inside MULTI is not allowed"),
+            _ if commands::is_write(args) && self.is_replica() => {
+                client.transaction_failed = true;
+                read_only()
+            }
+            _ if commands::exists(name) || name == "PUBLISH" => {
+                client
+                    .transaction
+                    .as_mut()
+                    .expect("in MULTI")
+                    .push(args.to_vec());
+                Value::Simple("QUEUED".to_string())
             }
             _ => {
-                format!("ERROR: Unknown command '{}'", args[0])
+                client.transaction_failed = true;
+                Value::error(format!(
+                    "ERR Command '{}' not allowed inside a transaction",
+                    name.to_lowercase()
+                ))
             }
         }
     }
 
-    fn handle_client(&mut self, mut stream: TcpStream) -> io::Result<()> {
-        let mut reader = BufReader::new(stream.try_clone()?);
-        let mut line = String::new();
+    /// Runs the queued commands under a single lock on the dataset, unless a
+    /// watched key changed since it was watched.
+    fn exec(&self, client: &mut Client) -> Value {
+        let queued = client.transaction.take().unwrap_or_default();
+        let failed = std::mem::take(&mut client.transaction_failed);
+        let watched = std::mem::take(&mut client.watched);
 
-        loop {
-            line.clear();
-            match reader.read_line(&mut line) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let response = self.process(line.clone());
-                    writeln!(stream, "{}", response)?;
-                    stream.flush()?;
+        let mut db = self.db.lock().unwrap();
+        let unchanged = watched
+            .iter()
+            .all(|(key, version)| db.version(key) == *version);
+        for (key, _) in &watched {
+            db.unwatch(key);
+        }
+        if failed {
+            return Value::error("EXECABORT Transaction discarded because of previous errors.");
+        }
+        if !unchanged {
+            return Value::NullArray;
+        }
+
+        let mut writes = Vec::new();
+        let replies = queued
+            .iter()
+            .map(|args| {
+                if args[0].eq_ignore_ascii_case(b"PUBLISH") {
+                    self.publish(args)
+                } else {
+                    self.apply(&mut db, args, &mut writes)
                 }
-                Err(e) => {
-                    eprintln!("Error reading from client: {}", e);
+            })
+            .collect();
+        self.propagate(&writes);
+        Value::Array(replies)
+    }
+
+    fn unwatch_all(&self, client: &mut Client) {
+        if client.watched.is_empty() {
+            return;
+        }
+        let mut db = self.db.lock().unwrap();
+        for (key, _) in client.watched.drain(..) {
+            db.unwatch(&key);
+        }
+    }
+
+    /// SUBSCRIBE/PSUBSCRIBE: confirms each channel with its own reply, as
+    /// Redis does, so this writes to the connection directly.
+    fn subscribe(&self, client: &mut Client, args: &[Vec<u8>], pattern: bool) {
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+        if args.len() < 2 {
+            let error = format!("ERR wrong number of arguments for '{}' command", kind);
+            client.connection.send(&Value::error(error));
+            return;
+        }
+        if pattern
+            && args[1..]
+                .iter()
+                .any(|channel| channel.len() > pubsub::MAX_PATTERN_LEN)
+        {
+            let error = format!("ERR pattern longer than {} bytes", pubsub::MAX_PATTERN_LEN);
+            client.connection.send(&Value::error(error));
+            return;
+        }
+
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for channel in &args[1..] {
+            let subscribed = if pattern {
+                &mut client.patterns
+            } else {
+                &mut client.channels
+            };
+            if subscribed.insert(channel.clone()) {
+                pubsub.subscribe(channel, &client.connection, pattern);
+            }
+            client.connection.send(&Value::Push(vec![
+                Value::bulk(kind),
+                Value::bulk(channel.clone()),
+                Value::Integer(client.subscriptions() as i64),
+            ]));
+        }
+    }
+
+    /// UNSUBSCRIBE/PUNSUBSCRIBE: with no arguments, drops every subscription
+    /// of that kind.
+    fn unsubscribe(&self, client: &mut Client, args: &[Vec<u8>], pattern: bool) {
+        let kind = if pattern {
+            "punsubscribe"
+        } else {
+            "unsubscribe"
+        };
+        let subscribed = if pattern {
+            &client.patterns
+        } else {
+            &client.channels
+        };
+        let channels: Vec<Vec<u8>> = if args.len() > 1 {
+            args[1..].to_vec()
+        } else {
+            subscribed.iter().cloned().collect()
+        };
+        if channels.is_empty() {
+            client.connection.send(&Value::Push(vec![
+                Value::bulk(kind),
+                Value::Null,
+                Value::Integer(client.subscriptions() as i64),
+            ]));
+            return;
+        }
+
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for channel in channels {
+            let subscribed = if pattern {
+                &mut client.patterns
+            } else {
+                &mut client.channels
+            };
+            if subscribed.remove(&channel) {
+                pubsub.unsubscribe(&channel, client.connection.id, pattern);
+            }
+            client.connection.send(&Value::Push(vec![
+                Value::bulk(kind),
+                Value::bulk(channel),
+                Value::Integer(client.subscriptions() as i64),
+            ]));
+        }
+    }
+
+    /// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+    fn pubsub_info(&self, args: &[Vec<u8>]) -> Value {
+        let subcommand = match args.get(1) {
+            Some(subcommand) => String::from_utf8_lossy(subcommand).to_uppercase(),
+            None => return Value::error("ERR wrong number of arguments for 'pubsub' command"),
+        };
+        let pubsub = self.pubsub.lock().unwrap();
+        match subcommand.as_str() {
+            "CHANNELS" if args.len() <= 3 => Value::Array(
+                pubsub
+                    .channels(args.get(2).map(Vec::as_slice))
+                    .into_iter()
+                    .map(Value::bulk)
+                    .collect(),
+            ),
+            "NUMSUB" => Value::Array(
+                args[2..]
+                    .iter()
+                    .flat_map(|channel| {
+                        let count = pubsub.subscriber_count(channel);
+                        [Value::bulk(channel.clone()), Value::Integer(count as i64)]
+                    })
+                    .collect(),
+            ),
+            "NUMPAT" if args.len() == 2 => Value::Integer(pubsub.pattern_count() as i64),
+            _ => Value::error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'",
+                subcommand.to_lowercase()
+            )),
+        }
+    }
+
+    fn call(&self, args: &[Vec<u8>]) -> Value {
+        let mut db = self.db.lock().unwrap();
+        let mut writes = Vec::new();
+        let reply = self.apply(&mut db, args, &mut writes);
+        self.propagate(&writes);
+        reply
+    }
+
+    /// Executes a keyspace command, collecting it in `writes` if it changed
+    /// the dataset and has to be propagated.
+    fn apply(&self, db: &mut Db, args: &[Vec<u8>], writes: &mut Vec<Vec<Vec<u8>>>) -> Value {
+        let args = commands::absolute_expiry(args);
+        let dirty = db.dirty();
+        let reply = commands::execute(db, &args);
+        if db.dirty() != dirty && commands::is_write(&args) {
+            writes.push(args);
+        }
+        reply
+    }
+
+    /// Sends the writes of one command or transaction to the AOF and the
+    /// replicas. Called with the `db` lock held.
+    fn propagate(&self, writes: &[Vec<Vec<u8>>]) {
+        if writes.is_empty() {
+            return;
+        }
+        let data = persistence::encode_writes(writes);
+        if let Some(aof) = self.aof.lock().unwrap().as_mut()
+            && let Err(e) = aof.append(&data)
+        {
+            eprintln!("Error writing to the AOF: {}", e);
+        }
+        self.replication.lock().unwrap().feed(&data);
+    }
+
+    /// Replaces the whole AOF with a rewrite of the dataset, such as one
+    /// just loaded from a leader.
+    fn rewrite_aof(&self) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        let mut aof = self.aof.lock().unwrap();
+        let Some(aof) = aof.as_mut() else {
+            return Ok(());
+        };
+        let rewritten = persistence::write_aof_rewrite(&self.config.aof_path(), &db.snapshot())?;
+        let len = aof.len();
+        aof.replace_prefix(&rewritten, len)
+    }
+
+    fn aof_len(&self) -> u64 {
+        self.aof.lock().unwrap().as_ref().map_or(0, Aof::len)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if self.background_job.lock().unwrap().is_some() {
+            return Err(io::Error::other("Background save already in progress"));
+        }
+        let db = self.db.lock().unwrap();
+        let result = persistence::save_snapshot(&self.config.snapshot_path(), db.iter());
+        self.stats.saved(db.dirty(), result.is_ok());
+        result
+    }
+
+    fn start_background_job(&self, job: BackgroundJob) -> bool {
+        let mut current = self.background_job.lock().unwrap();
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(job);
+        true
+    }
+
+    /// There is no fork() here, so the point-in-time copy is a clone of the
+    /// dataset taken under the lock; the slow disk I/O happens off-lock.
+    fn bgsave(self: &Arc<Self>) -> Value {
+        if !self.start_background_job(BackgroundJob::Save) {
+            return Value::error("ERR Background save already in progress");
+        }
+        let (entries, dirty) = {
+            let db = self.db.lock().unwrap();
+            (db.snapshot(), db.dirty())
+        };
+
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let entries = entries
+                .iter()
+                .map(|(key, value, expiry)| (key.as_slice(), value, *expiry));
+            let result = persistence::save_snapshot(&server.config.snapshot_path(), entries);
+            server.stats.saved(dirty, result.is_ok());
+            match result {
+                Ok(()) => println!("Background saving terminated with success"),
+                Err(e) => eprintln!("Background saving error: {}", e),
+            }
+            *server.background_job.lock().unwrap() = None;
+        });
+        Value::Simple("Background saving started".to_string())
+    }
+
+    fn bgrewriteaof(self: &Arc<Self>) -> Value {
+        if self.aof.lock().unwrap().is_none() {
+            return Value::error("ERR Append only file is disabled, start with --appendonly yes");
+        }
+        if !self.start_background_job(BackgroundJob::AofRewrite) {
+            return Value::error("ERR Background save or AOF rewrite already in progress");
+        }
+        let (entries, offset) = {
+            let db = self.db.lock().unwrap();
+            (db.snapshot(), self.aof_len())
+        };
+
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let result = persistence::write_aof_rewrite(&server.config.aof_path(), &entries)
+                .and_then(|rewritten| match server.aof.lock().unwrap().as_mut() {
+                    Some(aof) => aof.replace_prefix(&rewritten, offset),
+                    None => Ok(()),
+                });
+            server.stats.last_save.lock().unwrap().aof_rewrite_ok = result.is_ok();
+            match result {
+                Ok(()) => println!("Background AOF rewrite terminated with success"),
+                Err(e) => eprintln!("Background AOF rewrite error: {}", e),
+            }
+            *server.background_job.lock().unwrap() = None;
+        });
+        Value::Simple("Background append only file rewriting started".to_string())
+    }
+
+    fn handle_client(self: &Arc<Self>, client: &mut Client, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let args = match resp::read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    client.connection.send(&Value::error(format!("ERR {}", e)));
                     break;
                 }
+                Err(e) => return Err(e),
+            };
+
+            let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+            if name == "QUIT" {
+                client.connection.send(&Value::ok());
+                break;
             }
+            self.feed_monitors(client, &name, &args);
+            let started = Instant::now();
+            self.dispatch(client, &name, &args);
+            self.log_if_slow(client, &args, started.elapsed());
+            self.stats
+                .commands_processed
+                .fetch_add(1, Ordering::Relaxed);
+
+            let mut info = client.connection.info.lock().unwrap();
+            info.command = name.to_lowercase();
+            info.last_active = Instant::now();
+            info.subscriptions = client.channels.len();
+            info.patterns = client.patterns.len();
+            info.multi = client.transaction.as_ref().map(Vec::len);
         }
         Ok(())
     }
 
-    fn run(&mut self) -> io::Result<()> {
+    fn dispatch(self: &Arc<Self>, client: &mut Client, name: &str, args: &[Vec<u8>]) {
+        match name {
+            "PSYNC" | "SYNC" => self.full_sync(client),
+            "REPLCONF" => {
+                if let Some(reply) = self.replconf(client, args) {
+                    client.connection.send(&reply);
+                }
+            }
+            _ if client.transaction.is_some() => {
+                let reply = self.queue(client, name, args);
+                client.connection.send(&reply);
+            }
+            "SUBSCRIBE" => self.subscribe(client, args, false),
+            "PSUBSCRIBE" => self.subscribe(client, args, true),
+            "UNSUBSCRIBE" => self.unsubscribe(client, args, false),
+            "PUNSUBSCRIBE" => self.unsubscribe(client, args, true),
+            _ if client.in_subscriber_mode() && name != "PING" => {
+                client.connection.send(&Value::error(format!(
+                    "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                    name.to_lowercase()
+                )));
+            }
+            "MONITOR" => self.monitor(client),
+            _ => {
+                let reply = self.process(client, args);
+                client.connection.send(&reply);
+            }
+        }
+    }
+
+    fn accept(self: &Arc<Self>, mut stream: TcpStream) -> Option<JoinHandle<()>> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= self.config.max_clients {
+            // A client that doesn't read must not hold up everyone else.
+            drop(clients);
+            self.stats
+                .rejected_connections
+                .fetch_add(1, Ordering::Relaxed);
+            let reply = Value::error("ERR max number of clients reached");
+            let _ = stream.write_all(&reply.encode(2));
+            return None;
+        }
+
+        let client_id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let (connection, writer) = match Connection::open(client_id, &stream) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("Error setting up client: {}", e);
+                return None;
+            }
+        };
+        clients.insert(client_id, Arc::clone(&connection));
+        drop(clients);
+        self.stats
+            .connections_received
+            .fetch_add(1, Ordering::Relaxed);
+
+        let server = Arc::clone(self);
+        Some(thread::spawn(move || {
+            let mut client = Client::new(connection);
+            if let Err(e) = server.handle_client(&mut client, stream) {
+                eprintln!("Error handling client: {}", e);
+            }
+
+            server.unsubscribe_all(&client);
+            server.unwatch_all(&mut client);
+            server.replication.lock().unwrap().remove_replica(client_id);
+            server.monitors.lock().unwrap().remove(&client_id);
+            server.clients.lock().unwrap().remove(&client_id);
+            // The writer finishes once the last handle to the connection is
+            // gone and everything queued has been written.
+            drop(client);
+            let _ = writer.join();
+        }))
+    }
+
+    fn unsubscribe_all(&self, client: &Client) {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for channel in &client.channels {
+            pubsub.unsubscribe(channel, client.connection.id, false);
+        }
+        for pattern in &client.patterns {
+            pubsub.unsubscribe(pattern, client.connection.id, true);
+        }
+    }
+
+    fn spawn_active_expire(self: &Arc<Self>) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            while !server.shutdown.load(Ordering::SeqCst) {
+                thread::sleep(ACTIVE_EXPIRE_INTERVAL);
+                server.db.lock().unwrap().active_expire_cycle();
+            }
+        })
+    }
+
+    fn spawn_aof_fsync(self: &Arc<Self>) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            while !server.shutdown.load(Ordering::SeqCst) {
+                thread::sleep(AOF_FSYNC_INTERVAL);
+                if let Some(aof) = server.aof.lock().unwrap().as_mut()
+                    && let Err(e) = aof.fsync()
+                {
+                    eprintln!("Error syncing the AOF: {}", e);
+                }
+            }
+        })
+    }
+
+    fn run(self: &Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        let mut workers = vec![self.spawn_active_expire(), self.spawn_replication()];
+        if self.config.append_only && self.config.append_fsync == FsyncPolicy::EverySec {
+            workers.push(self.spawn_aof_fsync());
+        }
+        let mut handles: Vec<JoinHandle<()>> = Vec::new();
+
         loop {
-            match self.listener.accept() {
-                Ok((stream, _addr)) => {
-                    println!("New client connected");
-                    if let Err(e) = self.handle_client(stream) {
-                        eprintln!("Error handling client: {}", e);
-                    }
+            let accepted = listener.accept();
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            match accepted {
+                Ok((stream, addr)) => {
+                    println!("New client connected: {}", addr);
+                    handles.retain(|handle| !handle.is_finished());
+                    handles.extend(self.accept(stream));
                 }
                 Err(e) => {
                     eprintln!("Error accepting connection: {}", e);
                 }
             }
         }
+
+        // Stop reading new commands but let every client finish the ones it has
+        // already sent before the process exits.
+        handles.retain(|handle| !handle.is_finished());
+        println!("Shutting down, waiting for {} client(s)...", handles.len());
+        for connection in self.clients.lock().unwrap().values() {
+            connection.shutdown_read();
+        }
+        self.replication.lock().unwrap().close_link();
+        for handle in handles.into_iter().chain(workers) {
+            let _ = handle.join();
+        }
+
+        while self.background_job.lock().unwrap().is_some() {
+            thread::sleep(Duration::from_millis(50));
+        }
+        println!("Saving the final snapshot before exiting...");
+        if let Err(e) = self.save() {
+            eprintln!("Error saving snapshot: {}", e);
+        }
+        if let Some(aof) = self.aof.lock().unwrap().as_mut() {
+            aof.fsync()?;
+        }
+        println!("Bye!");
+        Ok(())
+    }
+}
+
+fn read_only() -> Value {
+    Value::error("READONLY You can't write against a read only replica.")
+}
+
+/// With the AOF on, it is a complete log of the dataset and is replayed on
+/// its own. Otherwise, or if there is no AOF yet, the last snapshot is
+/// loaded, and a new AOF starts out as a rewrite of it.
+fn load_dataset(config: &Config, db: &mut Db) -> io::Result<Option<Aof>> {
+    let aof_path = config.aof_path();
+    if config.append_only && fs::metadata(&aof_path).is_ok_and(|metadata| metadata.len() > 0) {
+        let commands = persistence::load_aof(&aof_path, db, config.aof_repair)?;
+        println!(
+            "Replayed {} command(s) from {}",
+            commands,
+            aof_path.display()
+        );
+        return Aof::open(aof_path, config.append_fsync).map(Some);
+    }
+
+    let keys = persistence::load_snapshot(&config.snapshot_path(), db)?;
+    println!(
+        "Loaded {} key(s) from {}",
+        keys,
+        config.snapshot_path().display()
+    );
+    if !config.append_only {
+        return Ok(None);
     }
+    let rewritten = persistence::write_aof_rewrite(&aof_path, &db.snapshot())?;
+    fs::rename(rewritten, &aof_path)?;
+    Aof::open(aof_path, config.append_fsync).map(Some)
 }
 
 fn main() -> io::Result<()> {
-    let mut server = RedisServer::new()?;
-    server.run()
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    fs::create_dir_all(&config.dir)?;
+    let mut db = Db::new();
+    let (db, aof) = match load_dataset(&config, &mut db) {
+        Ok(aof) => (db, aof),
+        Err(e) => {
+            eprintln!("Can't load the dataset: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let listener = TcpListener::bind((config.bind.as_str(), config.port))?;
+    let address = listener.local_addr()?;
+    println!("Redis server running at: {}", address);
+
+    let server = Arc::new(RedisServer::new(config, db, aof));
+    let handler_server = Arc::clone(&server);
+    ctrlc::set_handler(move || {
+        if handler_server.shutdown.swap(true, Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        // Wake up the accept loop so it notices the shutdown flag.
+        let mut wake = address;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect(wake);
+    })
+    .map_err(io::Error::other)?;
+
+    server.run(listener)
 }
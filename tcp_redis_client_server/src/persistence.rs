@@ -0,0 +1,487 @@
+use crate::commands;
+use crate::commands::format_score;
+use crate::config::FsyncPolicy;
+use crate::db::{Db, Object, now_ms};
+use crate::resp::{self, Value};
+use crate::zset::SortedSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TCPREDIS";
+const SNAPSHOT_VERSION: u8 = 1;
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const END_OF_FILE: u8 = 0xff;
+
+// FNV-1a, used as a cheap integrity check for snapshot files.
+struct Checksum(u64);
+
+impl Checksum {
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+struct SnapshotWriter<W: Write> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.checksum.update(data);
+        self.inner.write_all(data)
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_len(data.len())?;
+        self.write(data)
+    }
+
+    fn write_len(&mut self, len: usize) -> io::Result<()> {
+        self.write(&(len as u32).to_le_bytes())
+    }
+
+    fn write_object(&mut self, object: &Object) -> io::Result<()> {
+        match object {
+            Object::String(value) => self.write_bytes(value),
+            Object::List(list) => {
+                self.write_len(list.len())?;
+                list.iter()
+                    .try_for_each(|element| self.write_bytes(element))
+            }
+            Object::Hash(hash) => {
+                self.write_len(hash.len())?;
+                hash.iter().try_for_each(|(field, value)| {
+                    self.write_bytes(field)?;
+                    self.write_bytes(value)
+                })
+            }
+            Object::Set(set) => {
+                self.write_len(set.len())?;
+                set.iter().try_for_each(|member| self.write_bytes(member))
+            }
+            Object::ZSet(zset) => {
+                self.write_len(zset.len())?;
+                zset.iter().try_for_each(|(member, score)| {
+                    self.write_bytes(member)?;
+                    self.write(&score.to_le_bytes())
+                })
+            }
+        }
+    }
+}
+
+fn type_code(object: &Object) -> u8 {
+    match object {
+        Object::String(_) => TYPE_STRING,
+        Object::List(_) => TYPE_LIST,
+        Object::Hash(_) => TYPE_HASH,
+        Object::Set(_) => TYPE_SET,
+        Object::ZSet(_) => TYPE_ZSET,
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp-{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Writes a point-in-time snapshot to a temporary file and atomically
+/// renames it over `path`, so a crash mid-save never leaves a torn file.
+pub fn save_snapshot<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = (&'a [u8], &'a Object, Option<u64>)>,
+) -> io::Result<()> {
+    let temp = temp_path(path);
+    let writer = write_snapshot(BufWriter::new(File::create(&temp)?), entries)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+/// Writes the snapshot format to `inner`; also used to send the dataset to
+/// a replica.
+pub fn write_snapshot<'a, W: Write>(
+    inner: W,
+    entries: impl Iterator<Item = (&'a [u8], &'a Object, Option<u64>)>,
+) -> io::Result<W> {
+    let mut writer = SnapshotWriter {
+        inner,
+        checksum: Checksum(0xcbf29ce484222325),
+    };
+
+    writer.write(SNAPSHOT_MAGIC)?;
+    writer.write(&[SNAPSHOT_VERSION])?;
+    for (key, value, expiry) in entries {
+        writer.write(&[type_code(value)])?;
+        match expiry {
+            Some(at) => {
+                writer.write(&[1])?;
+                writer.write(&at.to_le_bytes())?;
+            }
+            None => writer.write(&[0])?,
+        }
+        writer.write_bytes(key)?;
+        writer.write_object(value)?;
+    }
+    writer.write(&[END_OF_FILE])?;
+    let checksum = writer.checksum.0;
+    writer.inner.write_all(&checksum.to_le_bytes())?;
+    Ok(writer.inner)
+}
+
+struct SnapshotReader<R: Read> {
+    inner: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    fn read<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        self.checksum.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_len(&mut self) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.read()?) as usize)
+    }
+
+    /// The length isn't covered by the checksum yet, so the buffer grows
+    /// with the bytes that are really there rather than to what it claims.
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_len()?;
+        if len as i64 > resp::MAX_BULK_LEN {
+            return Err(invalid(format!("String of {} bytes in snapshot", len)));
+        }
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.checksum.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_object(&mut self, kind: u8) -> io::Result<Object> {
+        Ok(match kind {
+            TYPE_STRING => Object::String(self.read_bytes()?),
+            TYPE_LIST => {
+                let len = self.read_len()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(self.read_bytes()?);
+                }
+                Object::List(list)
+            }
+            TYPE_HASH => {
+                let len = self.read_len()?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    let field = self.read_bytes()?;
+                    hash.insert(field, self.read_bytes()?);
+                }
+                Object::Hash(hash)
+            }
+            TYPE_SET => {
+                let len = self.read_len()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(self.read_bytes()?);
+                }
+                Object::Set(set)
+            }
+            TYPE_ZSET => {
+                let len = self.read_len()?;
+                let mut zset = SortedSet::default();
+                for _ in 0..len {
+                    let member = self.read_bytes()?;
+                    zset.insert(member, f64::from_le_bytes(self.read()?));
+                }
+                Object::ZSet(zset)
+            }
+            other => return Err(invalid(format!("Unknown value type {} in snapshot", other))),
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Loads a snapshot into `db`, skipping keys whose TTL passed while the
+/// server was down. Returns the number of keys loaded.
+pub fn load_snapshot(path: &Path, db: &mut Db) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    read_snapshot(BufReader::new(file), db)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Loads a snapshot written by `write_snapshot` into `db`, skipping keys
+/// that expired in the meantime. Returns the number of keys loaded.
+pub fn read_snapshot<R: Read>(inner: R, db: &mut Db) -> io::Result<usize> {
+    let mut reader = SnapshotReader {
+        inner,
+        checksum: Checksum(0xcbf29ce484222325),
+    };
+
+    if &reader.read::<8>()? != SNAPSHOT_MAGIC {
+        return Err(invalid("Not a snapshot".to_string()));
+    }
+    let [version] = reader.read()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(format!("Unsupported snapshot version {}", version)));
+    }
+
+    let now = now_ms();
+    let mut loaded = 0;
+    loop {
+        let [kind] = reader.read()?;
+        if kind == END_OF_FILE {
+            break;
+        }
+        let [has_expiry] = reader.read()?;
+        let expiry = match has_expiry {
+            0 => None,
+            _ => Some(u64::from_le_bytes(reader.read()?)),
+        };
+        let key = reader.read_bytes()?;
+        let value = reader.read_object(kind)?;
+        if expiry.is_some_and(|at| at <= now) {
+            continue;
+        }
+        db.set(key.clone(), value, false);
+        if let Some(at) = expiry {
+            db.set_expiry(&key, at);
+        }
+        loaded += 1;
+    }
+
+    let expected = reader.checksum.0;
+    let mut stored = [0; 8];
+    reader.inner.read_exact(&mut stored)?;
+    if u64::from_le_bytes(stored) != expected {
+        return Err(invalid("Snapshot checksum mismatch".to_string()));
+    }
+    Ok(loaded)
+}
+
+/// Encodes the writes of one command or transaction the way they are
+/// appended to the AOF and sent to replicas. Several writes are wrapped in
+/// MULTI/EXEC, so a transaction cut short by a crash is never half replayed.
+pub fn encode_writes(writes: &[Vec<Vec<u8>>]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if writes.len() > 1 {
+        data.extend(Value::command(&[b"MULTI".to_vec()]).encode(2));
+    }
+    for args in writes {
+        data.extend(Value::command(args).encode(2));
+    }
+    if writes.len() > 1 {
+        data.extend(Value::command(&[b"EXEC".to_vec()]).encode(2));
+    }
+    data
+}
+
+/// The append-only file. Every write command is appended in RESP form,
+/// with relative TTLs already turned into absolute deadlines.
+pub struct Aof {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    len: u64,
+    needs_fsync: bool,
+}
+
+impl Aof {
+    pub fn open(path: PathBuf, fsync: FsyncPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Aof {
+            path,
+            file,
+            fsync,
+            len,
+            needs_fsync: false,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends commands encoded by `encode_writes`.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        match self.fsync {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.needs_fsync = true,
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    /// Called once a second by the `everysec` policy and on shutdown.
+    pub fn fsync(&mut self) -> io::Result<()> {
+        if self.needs_fsync {
+            self.file.sync_data()?;
+            self.needs_fsync = false;
+        }
+        Ok(())
+    }
+
+    /// Replaces everything before `offset` with the contents of `prefix`,
+    /// keeping the commands appended after `offset`. Used by BGREWRITEAOF,
+    /// whose `prefix` is the compacted dataset.
+    pub fn replace_prefix(&mut self, prefix: &Path, offset: u64) -> io::Result<()> {
+        let temp = temp_path(&self.path);
+        fs::rename(prefix, &temp)?;
+        let mut output = OpenOptions::new().append(true).open(&temp)?;
+
+        let mut current = File::open(&self.path)?;
+        current.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut current, &mut output)?;
+        output.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+
+        *self = Aof::open(self.path.clone(), self.fsync)?;
+        Ok(())
+    }
+}
+
+/// Writes the dataset as the shortest command sequence that rebuilds it,
+/// starting with FLUSHALL so the file stands on its own.
+pub fn write_aof_rewrite(
+    path: &Path,
+    entries: &[(Vec<u8>, Object, Option<u64>)],
+) -> io::Result<PathBuf> {
+    let temp = temp_path(&path.with_extension("rewrite"));
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    let mut write = |args: Vec<Vec<u8>>| writer.write_all(&Value::command(&args).encode(2));
+
+    write(vec![b"FLUSHALL".to_vec()])?;
+    for (key, value, expiry) in entries {
+        let mut args = match value {
+            Object::String(_) => vec![b"SET".to_vec(), key.clone()],
+            Object::List(_) => vec![b"RPUSH".to_vec(), key.clone()],
+            Object::Hash(_) => vec![b"HSET".to_vec(), key.clone()],
+            Object::Set(_) => vec![b"SADD".to_vec(), key.clone()],
+            Object::ZSet(_) => vec![b"ZADD".to_vec(), key.clone()],
+        };
+        match value {
+            Object::String(value) => args.push(value.clone()),
+            Object::List(list) => args.extend(list.iter().cloned()),
+            Object::Hash(hash) => {
+                for (field, value) in hash {
+                    args.push(field.clone());
+                    args.push(value.clone());
+                }
+            }
+            Object::Set(set) => args.extend(set.iter().cloned()),
+            Object::ZSet(zset) => {
+                for (member, score) in zset.iter() {
+                    args.push(format_score(score));
+                    args.push(member.to_vec());
+                }
+            }
+        }
+        write(args)?;
+        if let Some(at) = expiry {
+            write(vec![
+                b"PEXPIREAT".to_vec(),
+                key.clone(),
+                at.to_string().into_bytes(),
+            ])?;
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    Ok(temp)
+}
+
+/// Replays the AOF into `db`. A file that ends in the middle of a command
+/// (e.g. the server was killed during a write) is refused unless `repair`
+/// is set, in which case it is truncated back to the last complete command.
+pub fn load_aof(path: &Path, db: &mut Db, repair: bool) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut replayed = 0;
+    let mut valid_len = 0;
+    // Commands of a MULTI/EXEC block are only applied once EXEC is read.
+    let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+
+    loop {
+        match resp::read_command(&mut reader) {
+            Ok(Some(args)) => {
+                let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+                let batch = match (name.as_str(), transaction.as_mut()) {
+                    ("MULTI", None) => {
+                        transaction = Some(Vec::new());
+                        continue;
+                    }
+                    ("EXEC", Some(_)) => transaction.take().unwrap_or_default(),
+                    (_, Some(queued)) => {
+                        queued.push(args);
+                        continue;
+                    }
+                    (_, None) => vec![args],
+                };
+                for args in batch {
+                    if let Value::Error(e) = commands::execute(db, &args) {
+                        eprintln!("AOF: command #{} failed: {}", replayed + 1, e);
+                    }
+                    replayed += 1;
+                }
+                valid_len = reader.stream_position()?;
+            }
+            Ok(None) if transaction.is_none() => return Ok(replayed),
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                return Err(invalid(format!(
+                    "Bad file format reading the append only file at byte {}: {}",
+                    valid_len, e
+                )));
+            }
+        }
+    }
+
+    if !repair {
+        return Err(invalid(format!(
+            "The append only file {} is truncated at byte {}. Start with --aof-repair to \
+             discard the incomplete command or transaction and load it anyway.",
+            path.display(),
+            valid_len
+        )));
+    }
+    eprintln!(
+        "AOF: truncated at byte {}, discarding the incomplete command or transaction",
+        valid_len
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(valid_len)?;
+    Ok(replayed)
+}
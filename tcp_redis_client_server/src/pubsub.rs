@@ -0,0 +1,188 @@
+use crate::config::OverflowPolicy;
+use crate::connection::Connection;
+use crate::resp::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Subscribers = HashMap<u64, Arc<Connection>>;
+
+/// The longest pattern PSUBSCRIBE accepts; every publish is matched
+/// against each of them.
+pub const MAX_PATTERN_LEN: usize = 1024;
+
+/// Channel and pattern subscriptions of every connected client.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<Vec<u8>, Subscribers>,
+    patterns: HashMap<Vec<u8>, Subscribers>,
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, channel: &[u8], connection: &Arc<Connection>, pattern: bool) {
+        let table = if pattern {
+            &mut self.patterns
+        } else {
+            &mut self.channels
+        };
+        table
+            .entry(channel.to_vec())
+            .or_default()
+            .insert(connection.id, Arc::clone(connection));
+    }
+
+    pub fn unsubscribe(&mut self, channel: &[u8], id: u64, pattern: bool) {
+        let table = if pattern {
+            &mut self.patterns
+        } else {
+            &mut self.channels
+        };
+        if let Some(subscribers) = table.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                table.remove(channel);
+            }
+        }
+    }
+
+    /// Pushes `message` to every subscriber of `channel` and of a matching
+    /// pattern. Returns how many clients it was delivered to.
+    pub fn publish(
+        &self,
+        channel: &[u8],
+        message: &[u8],
+        limit: usize,
+        overflow: OverflowPolicy,
+    ) -> usize {
+        let mut receivers = 0;
+        let mut deliver = |connection: &Arc<Connection>, push: Value| {
+            if connection.push(&push, limit) {
+                receivers += 1;
+            } else if overflow == OverflowPolicy::Disconnect {
+                eprintln!(
+                    "Disconnecting client {}: pub/sub output buffer limit reached",
+                    connection.id
+                );
+                connection.close();
+            }
+        };
+
+        if let Some(subscribers) = self.channels.get(channel) {
+            for connection in subscribers.values() {
+                let push = Value::Push(vec![
+                    Value::bulk("message"),
+                    Value::bulk(channel),
+                    Value::bulk(message),
+                ]);
+                deliver(connection, push);
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for connection in subscribers.values() {
+                let push = Value::Push(vec![
+                    Value::bulk("pmessage"),
+                    Value::bulk(pattern.clone()),
+                    Value::bulk(channel),
+                    Value::bulk(message),
+                ]);
+                deliver(connection, push);
+            }
+        }
+        receivers
+    }
+
+    /// Active channels, optionally only those matching `pattern`.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        self.channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscriber_count(&self, channel: &[u8]) -> usize {
+        self.channels
+            .get(channel)
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+/// Redis-style glob matching: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\`
+/// to escape the next character.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, t));
+            p += 1;
+            continue;
+        }
+        match match_byte(pattern, p, text[t]) {
+            Some(next) => {
+                p = next;
+                t += 1;
+            }
+            None => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `byte` against the pattern element at `p`, other than `*`.
+/// Returns where the next element starts.
+fn match_byte(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let (negate, mut class) = match &pattern[p + 1..] {
+                [b'^', class @ ..] => (true, class),
+                class => (false, class),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == byte;
+                        class = tail;
+                    }
+                    [start, b'-', end, tail @ ..] if *end != b']' => {
+                        let (low, high) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        matched |= (low..=high).contains(&byte);
+                        class = tail;
+                    }
+                    [c, tail @ ..] => {
+                        matched |= *c == byte;
+                        class = tail;
+                    }
+                }
+            }
+            (matched != negate).then_some(pattern.len() - class.len())
+        }
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        &c => (c == byte).then_some(p + 1),
+    }
+}
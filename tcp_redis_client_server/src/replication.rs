@@ -0,0 +1,417 @@
+use crate::connection::Connection;
+use crate::resp::{self, Value};
+use crate::{Client, RedisServer, persistence};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REPLICA_PING_INTERVAL: Duration = Duration::from_secs(10);
+const REPLICA_ACK_INTERVAL: Duration = Duration::from_secs(1);
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Replica {
+    connection: Arc<Connection>,
+    listening_port: u16,
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+/// Replication state. As a leader, `offset` counts the bytes of write
+/// commands fed to replicas; as a replica, the bytes received from the leader.
+pub struct Replication {
+    replid: String,
+    offset: u64,
+    master: Option<(String, u16)>,
+    link: Option<TcpStream>,
+    link_up: bool,
+    sync_in_progress: bool,
+    last_io: Instant,
+    replicas: HashMap<u64, Replica>,
+}
+
+/// A random 40 character hex id; `RandomState` is seeded from the OS.
+fn new_replid() -> String {
+    let mut replid = String::new();
+    for _ in 0..3 {
+        let _ = write!(
+            replid,
+            "{:016x}",
+            RandomState::new().build_hasher().finish()
+        );
+    }
+    replid.truncate(40);
+    replid
+}
+
+impl Replication {
+    pub fn new(master: Option<(String, u16)>) -> Self {
+        Replication {
+            replid: new_replid(),
+            offset: 0,
+            master,
+            link: None,
+            link_up: false,
+            sync_in_progress: false,
+            last_io: Instant::now(),
+            replicas: HashMap::new(),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Sends commands that were just applied to every replica. A replica
+    /// only forwards what it receives from its own leader, see `forward`.
+    pub fn feed(&mut self, data: &[u8]) {
+        if !self.is_replica() {
+            self.forward(data);
+        }
+    }
+
+    fn forward(&mut self, data: &[u8]) {
+        self.offset += data.len() as u64;
+        for replica in self.replicas.values() {
+            replica.connection.send_bytes(data.to_vec());
+        }
+    }
+
+    pub fn remove_replica(&mut self, id: u64) {
+        self.replicas.remove(&id);
+    }
+
+    /// Drops the link to the leader, which makes the replication thread
+    /// notice a REPLICAOF change or shutdown.
+    pub fn close_link(&mut self) {
+        if let Some(link) = self.link.take() {
+            let _ = link.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// The `# Replication` section of INFO.
+    pub fn info(&self) -> String {
+        let mut info = String::from("# Replication\r\n");
+        match &self.master {
+            Some((host, port)) => {
+                let _ = write!(
+                    info,
+                    "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n\
+                     master_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\n\
+                     master_sync_in_progress:{}\r\nslave_repl_offset:{}\r\n\
+                     slave_read_only:1\r\n",
+                    host,
+                    port,
+                    if self.link_up { "up" } else { "down" },
+                    self.last_io.elapsed().as_secs(),
+                    self.sync_in_progress as u8,
+                    self.offset
+                );
+            }
+            None => info.push_str("role:master\r\n"),
+        }
+        let _ = write!(info, "connected_slaves:{}\r\n", self.replicas.len());
+        let mut replicas: Vec<_> = self.replicas.values().collect();
+        replicas.sort_by_key(|replica| replica.connection.id);
+        for (i, replica) in replicas.iter().enumerate() {
+            let _ = write!(
+                info,
+                "slave{}:ip={},port={},state=online,offset={},lag={}\r\n",
+                i,
+                replica.connection.addr.ip(),
+                replica.listening_port,
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            );
+        }
+        let _ = write!(
+            info,
+            "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+            self.replid, self.offset
+        );
+        info
+    }
+}
+
+impl RedisServer {
+    /// PSYNC/SYNC from a replica: sends the whole dataset, then registers the
+    /// connection so it receives every write applied after that snapshot.
+    pub(crate) fn full_sync(&self, client: &mut Client) {
+        let db = self.db.lock().unwrap();
+        let snapshot = match persistence::write_snapshot(Vec::new(), db.iter()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                client.connection.send(&Value::error(format!("ERR {}", e)));
+                return;
+            }
+        };
+        let mut replication = self.replication.lock().unwrap();
+        let header = format!("FULLRESYNC {} {}", replication.replid, replication.offset);
+        let size = snapshot.len();
+        client.connection.info.lock().unwrap().replica = true;
+        client.connection.send(&Value::Simple(header));
+        client.connection.send(&Value::Bulk(snapshot));
+        replication.replicas.insert(
+            client.connection.id,
+            Replica {
+                connection: Arc::clone(&client.connection),
+                listening_port: client
+                    .listening_port
+                    .unwrap_or(client.connection.addr.port()),
+                ack_offset: 0,
+                last_ack: Instant::now(),
+            },
+        );
+        println!(
+            "Replica {} synchronized ({} bytes)",
+            client.connection.addr, size
+        );
+    }
+
+    /// REPLCONF listening-port <port> | ACK <offset> | capa ... ACKs get no
+    /// reply, as the replica never reads one.
+    pub(crate) fn replconf(&self, client: &mut Client, args: &[Vec<u8>]) -> Option<Value> {
+        if args.len() < 3 || args.len().is_multiple_of(2) {
+            return Some(Value::error(
+                "ERR wrong number of arguments for 'replconf' command",
+            ));
+        }
+        let option = String::from_utf8_lossy(&args[1]).to_lowercase();
+        let number = String::from_utf8_lossy(&args[2]).parse::<u64>().ok();
+        match (option.as_str(), number) {
+            ("ack", Some(offset)) => {
+                if let Some(replica) = self
+                    .replication
+                    .lock()
+                    .unwrap()
+                    .replicas
+                    .get_mut(&client.connection.id)
+                {
+                    replica.ack_offset = offset;
+                    replica.last_ack = Instant::now();
+                }
+                None
+            }
+            ("listening-port", Some(port)) if port <= u16::MAX as u64 => {
+                client.listening_port = Some(port as u16);
+                Some(Value::ok())
+            }
+            ("capa", _) => Some(Value::ok()),
+            _ => Some(Value::error(format!(
+                "ERR Unrecognized REPLCONF option: {}",
+                option
+            ))),
+        }
+    }
+
+    /// REPLICAOF host port | NO ONE
+    pub(crate) fn replicaof(&self, args: &[Vec<u8>]) -> Value {
+        if args.len() != 3 {
+            return Value::error("ERR wrong number of arguments for 'replicaof' command");
+        }
+        let host = String::from_utf8_lossy(&args[1]).into_owned();
+        let port = String::from_utf8_lossy(&args[2]).into_owned();
+        let master = if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            None
+        } else {
+            match port.parse() {
+                Ok(port) => Some((host, port)),
+                Err(_) => return Value::error("ERR Invalid master port"),
+            }
+        };
+
+        let mut replication = self.replication.lock().unwrap();
+        if replication.master == master {
+            return Value::ok();
+        }
+        if master.is_none() {
+            // Our history now diverges from the old leader's.
+            replication.replid = new_replid();
+            println!("Promoted to leader");
+        }
+        replication.master = master;
+        replication.link_up = false;
+        replication.close_link();
+        Value::ok()
+    }
+
+    /// Runs for the lifetime of the server: keeps a replica in sync with its
+    /// leader, reconnecting when the link drops, and lets a leader ping its
+    /// replicas so they can tell a quiet leader from a dead one.
+    pub(crate) fn spawn_replication(self: &Arc<Self>) -> thread::JoinHandle<()> {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let mut last_ping = Instant::now();
+            while !server.shutdown.load(Ordering::SeqCst) {
+                let master = server.replication.lock().unwrap().master.clone();
+                match master {
+                    Some((host, port)) => {
+                        let result = server.sync_with_master(&host, port);
+                        let mut replication = server.replication.lock().unwrap();
+                        replication.link_up = false;
+                        replication.sync_in_progress = false;
+                        // A link we closed ourselves (REPLICAOF, shutdown) is not an error.
+                        let closed = replication.master != Some((host.clone(), port))
+                            || server.shutdown.load(Ordering::SeqCst);
+                        drop(replication);
+                        if let Err(e) = result
+                            && !closed
+                        {
+                            eprintln!("Replication link to {}:{} lost: {}", host, port, e);
+                            thread::sleep(RECONNECT_DELAY);
+                        }
+                    }
+                    None => {
+                        thread::sleep(IDLE_POLL_INTERVAL);
+                        if last_ping.elapsed() >= REPLICA_PING_INTERVAL {
+                            let ping = Value::command(&[b"PING".to_vec()]).encode(2);
+                            let mut replication = server.replication.lock().unwrap();
+                            if !replication.replicas.is_empty() {
+                                replication.feed(&ping);
+                            }
+                            last_ping = Instant::now();
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn sync_with_master(self: &Arc<Self>, host: &str, port: u16) -> io::Result<()> {
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_read_timeout(Some(REPLICATION_TIMEOUT))?;
+        {
+            let mut replication = self.replication.lock().unwrap();
+            if replication.master.as_ref() != Some(&(host.to_string(), port)) {
+                return Ok(());
+            }
+            replication.link = Some(stream.try_clone()?);
+            replication.sync_in_progress = true;
+        }
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream.try_clone()?;
+        let mut request = |args: &[&str]| -> io::Result<Value> {
+            let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            writer.write_all(&Value::command(&args).encode(2))?;
+            match resp::read_value(&mut reader)? {
+                Some(Value::Error(e)) => Err(io::Error::other(e)),
+                Some(reply) => Ok(reply),
+                None => Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        };
+
+        request(&["PING"])?;
+        request(&["REPLCONF", "listening-port", &self.config.port.to_string()])?;
+        let (replid, offset) = match request(&["PSYNC", "?", "-1"])? {
+            Value::Simple(reply) => {
+                let mut parts = reply.split_whitespace();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some("FULLRESYNC"), Some(replid), Some(offset)) => (
+                        replid.to_string(),
+                        offset.parse::<u64>().map_err(io::Error::other)?,
+                    ),
+                    _ => return Err(io::Error::other(format!("Unexpected reply: {}", reply))),
+                }
+            }
+            _ => return Err(io::Error::other("Unexpected reply to PSYNC")),
+        };
+        let snapshot = match resp::read_value(&mut reader)? {
+            Some(Value::Bulk(snapshot)) => snapshot,
+            _ => return Err(io::Error::other("Expected the leader's snapshot")),
+        };
+
+        let keys = {
+            let mut db = self.db.lock().unwrap();
+            db.clear();
+            persistence::read_snapshot(&snapshot[..], &mut db)?
+        };
+        println!("Full sync from {}:{}: loaded {} key(s)", host, port, keys);
+        if let Err(e) = self.rewrite_aof() {
+            eprintln!("Error rewriting the AOF after the sync: {}", e);
+        }
+        {
+            let mut replication = self.replication.lock().unwrap();
+            replication.replid = replid;
+            replication.offset = offset;
+            replication.link_up = true;
+            replication.sync_in_progress = false;
+            replication.last_io = Instant::now();
+            // Our own replicas hold the old dataset; make them resync.
+            for replica in replication.replicas.values() {
+                replica.connection.close();
+            }
+        }
+
+        let acks = self.spawn_replica_acks(stream.try_clone()?);
+        let result = self.stream_from_master(&mut reader);
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = acks.join();
+        result
+    }
+
+    /// Applies the leader's write stream; MULTI/EXEC blocks are applied as a
+    /// whole, under one lock on the dataset.
+    fn stream_from_master(&self, reader: &mut BufReader<TcpStream>) -> io::Result<()> {
+        let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+        loop {
+            let args = match resp::read_command(reader)? {
+                Some(args) => args,
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+            let data = Value::command(&args).encode(2);
+            let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+            let batch = match (name.as_str(), transaction.as_mut()) {
+                ("MULTI", None) => {
+                    transaction = Some(Vec::new());
+                    None
+                }
+                ("EXEC", Some(_)) => transaction.take(),
+                (_, Some(queued)) => {
+                    queued.push(args);
+                    None
+                }
+                (_, None) => Some(vec![args]),
+            };
+
+            let mut db = self.db.lock().unwrap();
+            if let Some(batch) = batch {
+                let mut writes = Vec::new();
+                for args in &batch {
+                    self.apply(&mut db, args, &mut writes);
+                }
+                self.propagate(&writes);
+            }
+            let mut replication = self.replication.lock().unwrap();
+            replication.forward(&data);
+            replication.last_io = Instant::now();
+        }
+    }
+
+    /// Reports the replica's offset to the leader once a second, until the
+    /// link is closed.
+    fn spawn_replica_acks(self: &Arc<Self>, mut stream: TcpStream) -> thread::JoinHandle<()> {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(REPLICA_ACK_INTERVAL);
+                let offset = server.replication.lock().unwrap().offset;
+                let ack = Value::command(&[
+                    b"REPLCONF".to_vec(),
+                    b"ACK".to_vec(),
+                    offset.to_string().into_bytes(),
+                ]);
+                if stream.write_all(&ack.encode(2)).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
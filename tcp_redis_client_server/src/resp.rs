@@ -0,0 +1,358 @@
+use std::io::{self, BufRead, Read};
+
+pub const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+// Replies nest a few levels at most; this keeps a hostile peer from
+// recursing the reader off the end of the stack.
+const MAX_NESTING: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Value>),
+    Null,
+    NullArray,
+    Map(Vec<(Value, Value)>),
+    Push(Vec<Value>),
+}
+
+impl Value {
+    pub fn ok() -> Value {
+        Value::Simple("OK".to_string())
+    }
+
+    pub fn error(message: impl Into<String>) -> Value {
+        Value::Error(message.into())
+    }
+
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Value {
+        Value::Bulk(data.into())
+    }
+
+    pub fn command(args: &[Vec<u8>]) -> Value {
+        Value::Array(args.iter().map(|arg| Value::Bulk(arg.clone())).collect())
+    }
+
+    /// Encodes the value for a connection speaking RESP `protocol` (2 or 3).
+    /// RESP2 has no map, push or null type, so maps are flattened into arrays,
+    /// pushes are sent as plain arrays and nulls fall back to the null bulk
+    /// string / null array.
+    pub fn encode(&self, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out, protocol);
+        out
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>, protocol: u8) {
+        match self {
+            Value::Simple(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Error(s) => {
+                out.push(b'-');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Integer(n) => {
+                out.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+            }
+            Value::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_to(out, protocol);
+                }
+            }
+            Value::Push(items) => {
+                let kind = if protocol >= 3 { '>' } else { '*' };
+                out.extend_from_slice(format!("{}{}\r\n", kind, items.len()).as_bytes());
+                for item in items {
+                    item.write_to(out, protocol);
+                }
+            }
+            Value::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Value::Null => out.extend_from_slice(b"$-1\r\n"),
+            Value::NullArray if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            Value::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            Value::Map(pairs) => {
+                let header = if protocol >= 3 {
+                    format!("%{}\r\n", pairs.len())
+                } else {
+                    format!("*{}\r\n", pairs.len() * 2)
+                };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in pairs {
+                    key.write_to(out, protocol);
+                    value.write_to(out, protocol);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_INLINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        if line.len() as u64 >= MAX_INLINE_LEN {
+            return Err(protocol_error("too big inline request"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn parse_int(data: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid integer"))
+}
+
+/// Reads a bulk string's `len` bytes and its CRLF. The buffer grows as the
+/// data arrives rather than trusting the announced length up front.
+fn read_bulk<R: BufRead>(reader: &mut R, len: i64) -> io::Result<Vec<u8>> {
+    if len > MAX_BULK_LEN {
+        return Err(protocol_error("invalid bulk length"));
+    }
+    let mut data = Vec::new();
+    reader
+        .by_ref()
+        .take(len as u64 + 2)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 != len as u64 + 2 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk string not terminated by CRLF"));
+    }
+    data.truncate(len as usize);
+    Ok(data)
+}
+
+/// Reads one RESP value. Returns `Ok(None)` when the peer closed the
+/// connection cleanly before sending anything.
+pub fn read_value<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    read_nested(reader, 0)
+}
+
+fn read_nested<R: BufRead>(reader: &mut R, depth: usize) -> io::Result<Option<Value>> {
+    if depth > MAX_NESTING {
+        return Err(protocol_error("too deeply nested reply"));
+    }
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let line = read_line(reader)?;
+    let (kind, rest) = match line.split_first() {
+        Some((kind, rest)) => (*kind, rest),
+        None => return Err(protocol_error("empty type line")),
+    };
+    let text = || String::from_utf8_lossy(rest).into_owned();
+
+    let value = match kind {
+        b'+' => Value::Simple(text()),
+        b'-' => Value::Error(text()),
+        b':' => Value::Integer(parse_int(rest)?),
+        b'_' => Value::Null,
+        b'$' => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                return Ok(Some(Value::Null));
+            }
+            Value::Bulk(read_bulk(reader, len)?)
+        }
+        b'*' | b'>' => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                return Ok(Some(Value::NullArray));
+            }
+            let mut items = Vec::with_capacity(len.min(1024) as usize);
+            for _ in 0..len {
+                items.push(read_nested(reader, depth + 1)?.ok_or(io::ErrorKind::UnexpectedEof)?);
+            }
+            if kind == b'>' {
+                Value::Push(items)
+            } else {
+                Value::Array(items)
+            }
+        }
+        b'%' => {
+            let len = parse_int(rest)?.max(0);
+            let mut pairs = Vec::with_capacity(len.min(1024) as usize);
+            for _ in 0..len {
+                let key = read_nested(reader, depth + 1)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+                let value = read_nested(reader, depth + 1)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+                pairs.push((key, value));
+            }
+            Value::Map(pairs)
+        }
+        other => {
+            return Err(protocol_error(&format!(
+                "unexpected type byte '{}'",
+                other as char
+            )));
+        }
+    };
+    Ok(Some(value))
+}
+
+/// Reads the next command from a client. Multibulk requests are what real
+/// clients send; anything else is treated as an inline command, which keeps
+/// `telnet`/`nc` sessions usable. Empty requests are skipped.
+pub fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let first = match reader.fill_buf()?.first() {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+
+        if first == b'*' {
+            let len = parse_int(&read_line(reader)?[1..])?;
+            if len > MAX_MULTIBULK_LEN {
+                return Err(protocol_error("invalid multibulk length"));
+            }
+            if len <= 0 {
+                continue;
+            }
+            // Like Redis, every argument must be a bulk string, so a request
+            // can't nest.
+            let mut args = Vec::with_capacity(len.min(1024) as usize);
+            for _ in 0..len {
+                let line = read_line(reader)?;
+                let Some((b'$', rest)) = line.split_first() else {
+                    let got = line
+                        .first()
+                        .map_or(String::new(), |&byte| (byte as char).to_string());
+                    return Err(protocol_error(&format!("expected '$', got '{}'", got)));
+                };
+                let len = parse_int(rest)?;
+                if len < 0 {
+                    return Err(protocol_error("invalid bulk length"));
+                }
+                args.push(read_bulk(reader, len)?);
+            }
+            return Ok(Some(args));
+        }
+
+        let line = read_line(reader)?;
+        match split_args(&line) {
+            Some(args) if args.is_empty() => continue,
+            Some(args) => return Ok(Some(args)),
+            None => return Err(protocol_error("unbalanced quotes in request")),
+        }
+    }
+}
+
+/// Splits a command line the way `redis-cli` does: whitespace separated,
+/// with "double quotes" supporting escapes and 'single quotes' taken
+/// literally. Returns `None` on unbalanced quotes.
+pub fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= line.len() {
+            return Some(args);
+        }
+
+        let mut current = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    match line.get(i)? {
+                        b'\\'
+                            if i + 3 < line.len()
+                                && line[i + 1] == b'x'
+                                && line[i + 2].is_ascii_hexdigit()
+                                && line[i + 3].is_ascii_hexdigit() =>
+                        {
+                            let hex = std::str::from_utf8(&line[i + 2..i + 4]).ok()?;
+                            current.push(u8::from_str_radix(hex, 16).ok()?);
+                            i += 4;
+                        }
+                        b'\\' => {
+                            let escaped = *line.get(i + 1)?;
+                            current.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other,
+                            });
+                            i += 2;
+                        }
+                        b'"' => {
+                            i += 1;
+                            break;
+                        }
+                        byte => {
+                            current.push(*byte);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            b'\'' => {
+                i += 1;
+                loop {
+                    match line.get(i)? {
+                        b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                            current.push(b'\'');
+                            i += 2;
+                        }
+                        b'\'' => {
+                            i += 1;
+                            break;
+                        }
+                        byte => {
+                            current.push(*byte);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    current.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        // A closing quote must be followed by a space or the end of line.
+        if i < line.len() && !line[i].is_ascii_whitespace() {
+            return None;
+        }
+        args.push(current);
+    }
+}
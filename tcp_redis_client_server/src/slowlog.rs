@@ -0,0 +1,99 @@
+use crate::db::now_ms;
+use crate::resp::Value;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Like Redis, only keep enough of each command to recognise it.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+struct Entry {
+    id: u64,
+    time: u64,
+    duration: Duration,
+    args: Vec<Vec<u8>>,
+    addr: String,
+    name: String,
+}
+
+/// Commands that took longer than `--slowlog-log-slower-than`, newest first.
+pub struct SlowLog {
+    entries: VecDeque<Entry>,
+    next_id: u64,
+    max_len: usize,
+}
+
+impl SlowLog {
+    pub fn new(max_len: usize) -> Self {
+        SlowLog {
+            entries: VecDeque::new(),
+            next_id: 0,
+            max_len,
+        }
+    }
+
+    pub fn record(&mut self, args: &[Vec<u8>], duration: Duration, addr: String, name: String) {
+        // Leave room for the "more arguments" marker.
+        let keep = if args.len() > MAX_ARGS {
+            MAX_ARGS - 1
+        } else {
+            MAX_ARGS
+        };
+        let mut kept: Vec<Vec<u8>> = args
+            .iter()
+            .take(keep)
+            .map(|arg| {
+                if arg.len() <= MAX_ARG_LEN {
+                    return arg.clone();
+                }
+                let mut truncated = arg[..MAX_ARG_LEN].to_vec();
+                let more = format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN);
+                truncated.extend_from_slice(more.as_bytes());
+                truncated
+            })
+            .collect();
+        if args.len() > MAX_ARGS {
+            let more = format!("... ({} more arguments)", args.len() - kept.len());
+            kept.push(more.into_bytes());
+        }
+
+        self.entries.push_front(Entry {
+            id: self.next_id,
+            time: now_ms() / 1000,
+            duration,
+            args: kept,
+            addr,
+            name,
+        });
+        self.next_id += 1;
+        self.entries.truncate(self.max_len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    /// SLOWLOG GET reply: the `count` most recent entries.
+    pub fn get(&self, count: usize) -> Value {
+        Value::Array(
+            self.entries
+                .iter()
+                .take(count)
+                .map(|entry| {
+                    Value::Array(vec![
+                        Value::Integer(entry.id as i64),
+                        Value::Integer(entry.time as i64),
+                        Value::Integer(entry.duration.as_micros() as i64),
+                        Value::Array(entry.args.iter().cloned().map(Value::Bulk).collect()),
+                        Value::bulk(entry.addr.clone()),
+                        Value::bulk(entry.name.clone()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
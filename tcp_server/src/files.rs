@@ -0,0 +1,151 @@
+use crate::http::{Body, Request, Response, Status, http_date, parse_http_date};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The directory pages are served from.
+pub struct DocRoot {
+    root: PathBuf,
+}
+
+impl DocRoot {
+    pub fn new(root: &str) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(DocRoot { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Answers a GET or HEAD with a file, `404.html` or a 304 if the
+    /// client's copy is still current.
+    pub fn serve(&self, request: &Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::error(Status::MethodNotAllowed).header("Allow", "GET, HEAD");
+        }
+        let Some(path) = self.resolve(&request.path) else {
+            return self.not_found();
+        };
+        match self.file_response(request, &path) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Error serving {}: {}", path.display(), e);
+                Response::error(Status::InternalError)
+            }
+        }
+    }
+
+    fn file_response(&self, request: &Request, path: &Path) -> io::Result<Response> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let (etag, modified) = validators(&metadata);
+        let response = Response::new(Status::Ok)
+            .header("ETag", &etag)
+            .header("Last-Modified", http_date(metadata.modified()?));
+
+        // If-None-Match wins over If-Modified-Since when both are sent.
+        let current = match request.header("If-None-Match") {
+            Some(tags) => tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+            }),
+            None => request
+                .header("If-Modified-Since")
+                .and_then(parse_http_date)
+                .is_some_and(|since| modified <= since),
+        };
+        if current {
+            return Ok(Response {
+                status: Status::NotModified,
+                ..response
+            });
+        }
+        Ok(Response {
+            body: Body::File(file, metadata.len()),
+            ..response.header("Content-Type", mime_type(path))
+        })
+    }
+
+    /// Maps a request path to a file below the root. A directory stands for
+    /// its `index.html`, and `/contact` may be `contact.html`. Dotfiles and
+    /// anything a symlink leads out of the root are not found.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in request_path.split('/') {
+            match segment {
+                "" | "." => {}
+                _ if segment.starts_with('.') || segment.contains('\\') => return None,
+                _ => path.push(segment),
+            }
+        }
+        if path.is_dir() {
+            path.push("index.html");
+        } else if !path.exists() && path.extension().is_none() {
+            path.set_extension("html");
+        }
+        let path = fs::canonicalize(path).ok()?;
+        (path.starts_with(&self.root) && path.is_file()).then_some(path)
+    }
+
+    fn not_found(&self) -> Response {
+        match fs::read(self.root.join("404.html")) {
+            Ok(page) => Response::new(Status::NotFound)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(page),
+            Err(_) => Response::error(Status::NotFound),
+        }
+    }
+}
+
+/// The ETag and modification time (in whole seconds, as HTTP dates have
+/// it) of a file.
+fn validators(metadata: &Metadata) -> (String, u64) {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len());
+    (etag, modified.as_secs())
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
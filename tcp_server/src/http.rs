@@ -0,0 +1,359 @@
+use std::io::{self, BufRead, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: u64 = 1024 * 1024;
+
+pub struct Request {
+    pub method: String,
+    /// Percent-decoded, without the query.
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request. Returns `Ok(None)` if the client closed the
+    /// connection without sending anything.
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Request>, Status> {
+        let mut remaining = MAX_HEAD_LEN;
+        let line = match read_line(reader, &mut remaining)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Status::BadRequest);
+        };
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(Status::BadRequest);
+        }
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            return Err(Status::VersionNotSupported);
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if !path.starts_with('/') {
+            return Err(Status::BadRequest);
+        }
+        let path = percent_decode(path, false).ok_or(Status::BadRequest)?;
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((percent_decode(key, true)?, percent_decode(value, true)?))
+            })
+            .collect::<Option<_>>()
+            .ok_or(Status::BadRequest)?;
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader, &mut remaining)?.ok_or(Status::BadRequest)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(Status::HeadersTooLarge);
+            }
+            let (name, value) = line.split_once(':').ok_or(Status::BadRequest)?;
+            if name.is_empty() || name.ends_with([' ', '\t']) {
+                return Err(Status::BadRequest);
+            }
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method: method.to_string(),
+            path,
+            query,
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+        };
+        if request.header("Transfer-Encoding").is_some() {
+            return Err(Status::NotImplemented);
+        }
+        if let Some(length) = request.header("Content-Length") {
+            let length: u64 = length.parse().map_err(|_| Status::BadRequest)?;
+            if length > MAX_BODY_LEN {
+                return Err(Status::PayloadTooLarge);
+            }
+            reader
+                .take(length)
+                .read_to_end(&mut request.body)
+                .map_err(|_| Status::BadRequest)?;
+            if (request.body.len() as u64) < length {
+                return Err(Status::BadRequest);
+            }
+        }
+        Ok(Some(request))
+    }
+
+    /// The first header called `name`, in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a CRLF or LF terminated line out of the `remaining` budget for the
+/// request line and headers.
+fn read_line(reader: &mut impl BufRead, remaining: &mut u64) -> Result<Option<String>, Status> {
+    let mut line = Vec::new();
+    let n = reader
+        .take(*remaining)
+        .read_until(b'\n', &mut line)
+        .map_err(|_| Status::RequestTimeout)?;
+    *remaining -= n as u64;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(match *remaining {
+            0 => Status::HeadersTooLarge,
+            _ => Status::BadRequest,
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| Status::BadRequest)
+}
+
+/// Decodes `%XX` escapes, and in a query `+` as a space.
+fn percent_decode(text: &str, plus_is_space: bool) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_is_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    NotModified,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    InternalError,
+    NotImplemented,
+    VersionNotSupported,
+}
+
+impl Status {
+    pub fn code(self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::NotModified => 304,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::RequestTimeout => 408,
+            Status::PayloadTooLarge => 413,
+            Status::HeadersTooLarge => 431,
+            Status::InternalError => 500,
+            Status::NotImplemented => 501,
+            Status::VersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NotModified => "Not Modified",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::RequestTimeout => "Request Timeout",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::HeadersTooLarge => "Request Header Fields Too Large",
+            Status::InternalError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::VersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+}
+
+pub struct Response {
+    pub status: Status,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Streamed from a file of the given length.
+    File(std::fs::File, u64),
+}
+
+impl Response {
+    pub fn new(status: Status) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+
+    /// A short plain text page for an error status.
+    pub fn error(status: Status) -> Self {
+        let body = format!("{} {}\n", status.code(), status.reason());
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.into_bytes())
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    /// Sends the response, leaving out the body for a HEAD request but not
+    /// its length. Every connection serves one request.
+    pub fn write(self, writer: &mut impl Write, head_only: bool) -> io::Result<()> {
+        let length = match &self.body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, length) => *length,
+        };
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.code(),
+            self.status.reason()
+        );
+        head.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.status != Status::NotModified {
+            head.push_str(&format!("Content-Length: {}\r\n", length));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        writer.write_all(head.as_bytes())?;
+        if !head_only {
+            match self.body {
+                Body::Empty => {}
+                Body::Bytes(bytes) => writer.write_all(&bytes)?,
+                Body::File(file, length) => {
+                    io::copy(&mut file.take(length), writer)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time as an IMF-fixdate, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an IMF-fixdate into seconds since the epoch. The obsolete
+/// formats browsers no longer send are not understood, nor are years
+/// outside 1970 to 9999.
+pub fn parse_http_date(text: &str) -> Option<u64> {
+    let parts: Vec<&str> = text.split([' ', ':']).collect();
+    let [_, day, month, year, hour, minute, second, "GMT"] = parts[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|&name| name == month)? as i64 + 1;
+    let year = year
+        .parse()
+        .ok()
+        .filter(|year| (1970..=9999).contains(year))?;
+    let day = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let (hour, minute, second): (i64, i64, i64) = (
+        hour.parse().ok().filter(|hour| (0..24).contains(hour))?,
+        minute
+            .parse()
+            .ok()
+            .filter(|minute| (0..60).contains(minute))?,
+        // 60 for a leap second.
+        second
+            .parse()
+            .ok()
+            .filter(|second| (0..=60).contains(second))?,
+    );
+    let secs = days_from_civil(year, month, day)
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    u64::try_from(secs).ok()
+}
+
+// The two below follow Howard Hinnant's date algorithms.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
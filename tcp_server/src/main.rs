@@ -1,21 +1,53 @@
-use std::io::{BufRead, BufReader, Write};
+mod files;
+mod http;
+mod pool;
+
+use files::DocRoot;
+use http::{Request, Response};
+use pool::ThreadPool;
+use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
-use std::{fs, thread};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, process, thread};
+
+// How long a client may take to send its request or read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() {
+    let doc_root = env::var("DOC_ROOT").unwrap_or_else(|_| "public".to_string());
+    let root = match DocRoot::new(&doc_root) {
+        Ok(root) => Arc::new(root),
+        Err(e) => {
+            eprintln!("Error: DOC_ROOT {}: {}", doc_root, e);
+            process::exit(1);
+        }
+    };
+    let workers = match env::var("WORKERS") {
+        Ok(workers) => match workers.parse::<usize>() {
+            Ok(workers) if workers > 0 => workers,
+            _ => {
+                eprintln!("Error: WORKERS must be a positive number, got {}", workers);
+                process::exit(1);
+            }
+        },
+        Err(_) => thread::available_parallelism().map_or(4, |n| n.get()),
+    };
+    let pool = ThreadPool::new(workers, workers * 16);
+
     let listener = match TcpListener::bind("0.0.0.0:6341") {
         Ok(listener) => listener,
         Err(_) => TcpListener::bind("0.0.0.0:0").expect("Could not assign any port."),
     };
     let port = listener.local_addr().unwrap().port();
     println!("TCP Server listening: http://127.0.0.1:{}", port);
+    println!("Serving {} with {} workers", root.path().display(), workers);
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                thread::spawn(move || {
-                    handle_client(stream);
-                });
+                let root = Arc::clone(&root);
+                pool.execute(move || handle_client(stream, &root));
             }
             Err(e) => {
                 eprintln!("Error: {}", e);
@@ -24,32 +56,51 @@ fn main() {
     }
 }
 
-fn handle_client(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let request = match buf_reader.lines().next() {
-        Some(Ok(line)) => line,
-        Some(Err(e)) => {
-            eprintln!("Error reading request: {}", e);
-            return;
+fn handle_client(mut stream: TcpStream, root: &DocRoot) {
+    if let Err(e) = stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+    {
+        eprintln!("Error setting up connection: {}", e);
+        return;
+    }
+    let mut reader = BufReader::new(&stream);
+    let (response, head_only) = match Request::read(&mut reader) {
+        Ok(Some(request)) => {
+            let response = root.serve(&request);
+            println!(
+                "{} {}{} {} -> {}{}",
+                request.method,
+                request.path,
+                query_string(&request),
+                request.version,
+                response.status.code(),
+                match request.body.len() {
+                    0 => String::new(),
+                    n => format!(" ({} byte body ignored)", n),
+                }
+            );
+            (response, request.method == "HEAD")
         }
-        None => {
-            eprintln!("No request line received");
-            return;
+        Ok(None) => return,
+        Err(status) => {
+            println!("Rejected request -> {}", status.code());
+            (Response::error(status), false)
         }
     };
-    println!("{}", request);
-    let (file, code, status) = match request.as_str() {
-        "GET / HTTP/1.1" => ("index.html", 200, "OK"),
-        "GET /contact HTTP/1.1" => ("contact.html", 200, "OK"),
-        _ => ("404.html", 404, "NOT FOUND"),
-    };
-
-    let contents = fs::read_to_string(file).unwrap();
-    let length = contents.len();
+    if let Err(e) = response.write(&mut stream, head_only) {
+        eprintln!("Error writing response: {}", e);
+    }
+}
 
-    let response = format!(
-        "HTTP/1.1 {} {}\r\nContent-Type:text/html\r\nContent-Length: {}\r\n\r\n{}",
-        code, status, length, contents
-    );
-    stream.write_all(response.as_bytes()).unwrap();
+fn query_string(request: &Request) -> String {
+    let pairs: Vec<String> = request
+        .query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    match pairs.is_empty() {
+        true => String::new(),
+        false => format!("?{}", pairs.join("&")),
+    }
 }
@@ -0,0 +1,44 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of worker threads. Jobs wait in a bounded queue, and
+/// `execute` blocks while it is full, so a flood of connections waits in
+/// the listen backlog instead of piling up threads.
+pub struct ThreadPool {
+    sender: SyncSender<Job>,
+}
+
+impl ThreadPool {
+    pub fn new(workers: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for id in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || work(&receiver))
+                .expect("Could not start a worker thread.");
+        }
+        ThreadPool { sender }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // The workers only stop once the pool is gone.
+        let _ = self.sender.send(Box::new(job));
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // A panicking job must not take its worker down with it.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
@@ -0,0 +1,457 @@
+use crate::{AVAILABLE_FILES, NodeStatus, Server, VERSION, format_duration, unix_time};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How long a client gets to send its request, and how much of it is read.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+// A quiet event stream gets a comment this often, so proxies keep it open
+// and a client that went away is noticed.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+// Admin clients served at once; an `/events` client holds its place until
+// it goes away. Further connections wait in the listen backlog.
+const MAX_CONNECTIONS: usize = 16;
+const JSON: &str = "application/json";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Joined,
+    Rejoined,
+    Left,
+    /// The node missed its heartbeats.
+    Inactive,
+    /// An inactive node sent a heartbeat again.
+    Back,
+    /// The node became the master.
+    Elected,
+}
+
+impl EventKind {
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::Joined => "joined",
+            EventKind::Rejoined => "rejoined",
+            EventKind::Left => "left",
+            EventKind::Inactive => "inactive",
+            EventKind::Back => "back",
+            EventKind::Elected => "elected",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub node_id: String,
+    pub address: String,
+    pub at: u64,
+}
+
+/// Membership changes seen by this node since it started, for `/events`
+/// and `/metrics`.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+    counts: Mutex<BTreeMap<EventKind, u64>>,
+}
+
+impl Events {
+    pub fn publish(&self, event: EventKind, node_id: &str, address: &str) {
+        *self.counts.lock().unwrap().entry(event).or_default() += 1;
+        let event = Event {
+            event,
+            node_id: node_id.to_string(),
+            address: address.to_string(),
+            at: unix_time(),
+        };
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl Server {
+    /// Serves the admin API on the `admin` address. Every node with one
+    /// listens, so the API moves with the master, but only the master
+    /// answers.
+    pub fn serve_admin(self: Arc<Self>) {
+        let Some(listener) = &self.admin else {
+            return;
+        };
+        let slots = Arc::new(Slots::default());
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = Slots::acquire(&slots);
+                    let server = Arc::clone(&self);
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        if let Err(e) = server.handle_admin(stream) {
+                            eprintln!("Error handling admin request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Admin connection error: {}", e),
+            }
+        }
+    }
+
+    fn handle_admin(&self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        // An event stream whose client stopped reading fails here instead of
+        // blocking forever.
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_LEN));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // No route takes headers or a body.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+        let Some(target) = target else {
+            return respond(&mut stream, "400 Bad Request", JSON, &error("Bad request"));
+        };
+        if method != "GET" {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                JSON,
+                &error("Only GET is supported"),
+            );
+        }
+        if let Some(master) = self.master() {
+            let body = json!({ "error": "This node is not the master", "master": master });
+            return respond(
+                &mut stream,
+                "503 Service Unavailable",
+                JSON,
+                &body.to_string(),
+            );
+        }
+
+        let path = target.split('?').next().unwrap_or_default();
+        match path {
+            "/" => respond(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                &self.dashboard()?,
+            ),
+            "/nodes" => {
+                let body = json!({
+                    "master": {
+                        "node_id": self.config.node_id,
+                        "address": self.address.to_string(),
+                        "version": VERSION,
+                        "started_at": self.started_at,
+                    },
+                    "nodes": self.node_statuses()?,
+                });
+                respond(&mut stream, "200 OK", JSON, &body.to_string())
+            }
+            "/events" => Ok(self.stream_events(stream)?),
+            "/metrics" => respond(
+                &mut stream,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                &self.metrics()?,
+            ),
+            _ => match path.strip_prefix("/nodes/") {
+                Some(node_id) => {
+                    let nodes = self.node_statuses()?;
+                    match nodes.iter().find(|node| node.node_id == node_id) {
+                        Some(node) => {
+                            respond(&mut stream, "200 OK", JSON, &serde_json::to_string(node)?)
+                        }
+                        None => respond(&mut stream, "404 Not Found", JSON, &error("Unknown node")),
+                    }
+                }
+                None => respond(&mut stream, "404 Not Found", JSON, &error("Not found")),
+            },
+        }
+    }
+
+    /// Sends every event from now on as server-sent events, until the
+    /// client goes away.
+    fn stream_events(&self, mut stream: TcpStream) -> io::Result<()> {
+        let events = self.events.subscribe();
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        )?;
+        loop {
+            match events.recv_timeout(KEEPALIVE) {
+                Ok(event) => write!(
+                    stream,
+                    "event: {}\ndata: {}\n\n",
+                    event.event.name(),
+                    serde_json::to_string(&event)?
+                )?,
+                Err(RecvTimeoutError::Timeout) => stream.write_all(b": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    /// The swarm in the Prometheus text format.
+    fn metrics(&self) -> Result<String, Box<dyn Error>> {
+        let (active, inactive, left, files) = {
+            let db = self.database.lock().unwrap();
+            let counts = db.query_row(
+                "SELECT IFNULL(SUM(has_left = 0 AND is_active = 1), 0),
+                        IFNULL(SUM(has_left = 0 AND is_active = 0), 0),
+                        IFNULL(SUM(has_left = 1), 0)
+                 FROM servers",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, u64>(0)?,
+                        row.get::<_, u64>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                },
+            )?;
+            let files: u64 =
+                db.query_row(&format!("SELECT COUNT(*) {}", AVAILABLE_FILES), [], |row| {
+                    row.get(0)
+                })?;
+            (counts.0, counts.1, counts.2, files)
+        };
+        let now = unix_time();
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP swarm_nodes Nodes that joined the swarm, by state."
+        )?;
+        writeln!(out, "# TYPE swarm_nodes gauge")?;
+        writeln!(out, "swarm_nodes{{state=\"active\"}} {}", active)?;
+        writeln!(out, "swarm_nodes{{state=\"inactive\"}} {}", inactive)?;
+        writeln!(out, "swarm_nodes{{state=\"left\"}} {}", left)?;
+        writeln!(
+            out,
+            "# HELP swarm_shared_files Files available from active nodes."
+        )?;
+        writeln!(out, "# TYPE swarm_shared_files gauge")?;
+        writeln!(out, "swarm_shared_files {}", files)?;
+        writeln!(
+            out,
+            "# HELP swarm_master_uptime_seconds Time since the master started."
+        )?;
+        writeln!(out, "# TYPE swarm_master_uptime_seconds gauge")?;
+        writeln!(
+            out,
+            "swarm_master_uptime_seconds {}",
+            now.saturating_sub(self.started_at)
+        )?;
+
+        writeln!(
+            out,
+            "# HELP swarm_events_total Membership changes since the master started."
+        )?;
+        writeln!(out, "# TYPE swarm_events_total counter")?;
+        let counts = self.events.counts.lock().unwrap().clone();
+        for (event, count) in counts {
+            writeln!(
+                out,
+                "swarm_events_total{{event=\"{}\"}} {}",
+                event.name(),
+                count
+            )?;
+        }
+
+        let nodes = self.node_statuses()?;
+        writeln!(
+            out,
+            "# HELP swarm_node_up Whether the node sends heartbeats."
+        )?;
+        writeln!(out, "# TYPE swarm_node_up gauge")?;
+        for node in &nodes {
+            writeln!(
+                out,
+                "swarm_node_up{{{}}} {}",
+                labels(node),
+                node.is_active as u8
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP swarm_node_last_seen_seconds Time since the node's last heartbeat."
+        )?;
+        writeln!(out, "# TYPE swarm_node_last_seen_seconds gauge")?;
+        for node in &nodes {
+            if let Some(last_seen) = node.last_seen {
+                let ago = now.saturating_sub(last_seen);
+                writeln!(
+                    out,
+                    "swarm_node_last_seen_seconds{{{}}} {}",
+                    labels(node),
+                    ago
+                )?;
+            }
+        }
+        writeln!(
+            out,
+            "# HELP swarm_node_latency_ms Round trip time of the node's heartbeat."
+        )?;
+        writeln!(out, "# TYPE swarm_node_latency_ms gauge")?;
+        for node in &nodes {
+            if let Some(latency_ms) = node.latency_ms {
+                writeln!(
+                    out,
+                    "swarm_node_latency_ms{{{}}} {}",
+                    labels(node),
+                    latency_ms
+                )?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// A page with the `status` of every node, refreshed every few seconds.
+    fn dashboard(&self) -> Result<String, Box<dyn Error>> {
+        let now = unix_time();
+        let mut rows = String::new();
+        for node in self.node_statuses()? {
+            let last_seen = match node.last_seen {
+                Some(last_seen) => {
+                    format!("{} ago", format_duration(now.saturating_sub(last_seen)))
+                }
+                None => "never".to_string(),
+            };
+            writeln!(
+                rows,
+                "<tr><td>{}:{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&node.ip),
+                escape(&node.port),
+                if node.is_active { "active" } else { "inactive" },
+                escape(node.version.as_deref().unwrap_or("unknown")),
+                last_seen,
+                node.latency_ms
+                    .map_or("-".to_string(), |ms| format!("{} ms", ms)),
+                escape(&node.node_id),
+            )?;
+        }
+        Ok(format!(
+            "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"5\">
+<title>Swarm</title>
+<style>body {{ font-family: sans-serif; }} td, th {{ padding: 4px 12px; text-align: left; }}</style>
+</head>
+<body>
+<h1>Swarm</h1>
+<p>Master {} up {}, version {}</p>
+<table>
+<tr><th>Address</th><th>State</th><th>Version</th><th>Last seen</th><th>Latency</th><th>Node</th></tr>
+{}</table>
+</body>
+</html>
+",
+            self.address,
+            format_duration(now.saturating_sub(self.started_at)),
+            VERSION,
+            rows
+        ))
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn error(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+fn labels(node: &NodeStatus) -> String {
+    format!(
+        "node_id=\"{}\",address=\"{}\"",
+        label_value(&node.node_id),
+        label_value(&format!("{}:{}", node.ip, node.port))
+    )
+}
+
+/// Escapes a Prometheus label value.
+fn label_value(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts the admin connections being served, so the listener stops
+/// accepting at `MAX_CONNECTIONS`.
+#[derive(Default)]
+struct Slots {
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// Waits for a free slot, held until the guard is dropped.
+    fn acquire(slots: &Arc<Slots>) -> SlotGuard {
+        let mut running = slots.running.lock().unwrap();
+        while *running >= MAX_CONNECTIONS {
+            running = slots.freed.wait(running).unwrap();
+        }
+        *running += 1;
+        SlotGuard {
+            slots: Arc::clone(slots),
+        }
+    }
+}
+
+struct SlotGuard {
+    slots: Arc<Slots>,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        *self.slots.running.lock().unwrap() -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,55 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::io;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 32;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A fresh challenge for one connection.
+pub fn nonce() -> io::Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(to_hex(&nonce))
+}
+
+fn mac(key: &str, nonce: &str, id: u64, body: &impl Serialize) -> serde_json::Result<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(id.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(&serde_json::to_vec(body)?);
+    Ok(mac)
+}
+
+/// Signs message `id`, a request or a reply, for the `nonce` the other end
+/// chose for this connection. The swarm key never goes over the wire, and
+/// the signature can't be replayed for another connection or another
+/// message.
+pub fn sign(key: &str, nonce: &str, id: u64, body: &impl Serialize) -> serde_json::Result<String> {
+    Ok(to_hex(&mac(key, nonce, id, body)?.finalize().into_bytes()))
+}
+
+/// Checks a signature in constant time.
+pub fn verify(key: &str, nonce: &str, id: u64, body: &impl Serialize, signature: &str) -> bool {
+    match (from_hex(signature), mac(key, nonce, id, body)) {
+        (Some(signature), Ok(mac)) => mac.verify_slice(&signature).is_ok(),
+        _ => false,
+    }
+}
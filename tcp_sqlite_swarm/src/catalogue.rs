@@ -0,0 +1,185 @@
+use crate::protocol::{Connection, ErrorCode, ErrorReply, Request, Response, Session};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const PART_SUFFIX: &str = ".part";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedFile {
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Hashes of the files seen by the last scan, so unchanged files are not
+/// read again.
+#[derive(Default)]
+pub struct Scanner {
+    known: HashMap<String, (u64, SystemTime, String)>,
+}
+
+impl Scanner {
+    pub fn scan(&mut self, dir: &Path) -> io::Result<Vec<SharedFile>> {
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        let mut known = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if !metadata.is_file() || !is_valid_name(&name) {
+                continue;
+            }
+
+            let size = metadata.len();
+            let modified = metadata.modified()?;
+            let hash = match self.known.remove(&name) {
+                Some((known_size, known_modified, hash))
+                    if known_size == size && known_modified == modified =>
+                {
+                    hash
+                }
+                _ => sha256_file(&entry.path())?,
+            };
+            known.insert(name.clone(), (size, modified, hash.clone()));
+            files.push(SharedFile { name, size, hash });
+        }
+        self.known = known;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+}
+
+/// A name that can be shared: a plain file name inside the share directory
+/// that prints on one line.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(PART_SUFFIX)
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control)
+}
+
+pub fn shared_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name)
+}
+
+pub fn part_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}{}", name, PART_SUFFIX))
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Answers request `id`, a `Get`, with a `File` reply followed by the
+/// file's bytes from `offset` on. Nothing can follow on the connection, in
+/// case the file shrinks while it is sent.
+pub fn serve_file(
+    stream: &mut impl Write,
+    session: &Session,
+    dir: &Path,
+    id: u64,
+    offset: u64,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    match open_shared(dir, offset, name) {
+        Ok((file, size)) => {
+            session.reply(stream, id, Ok(Response::File { size }))?;
+            io::copy(&mut file.take(size - offset), stream)?;
+        }
+        Err(e) => session.reply(stream, id, Err(e))?,
+    }
+    Ok(())
+}
+
+/// Opens a shared file at `offset`, along with its size.
+fn open_shared(dir: &Path, offset: u64, name: &str) -> Result<(File, u64), ErrorReply> {
+    if !is_valid_name(name) {
+        return Err(ErrorReply::new(ErrorCode::BadRequest, "Invalid file name"));
+    }
+    let mut file = File::open(shared_path(dir, name))
+        .map_err(|_| ErrorReply::new(ErrorCode::NotFound, "File not found"))?;
+    let internal = |e: io::Error| ErrorReply::new(ErrorCode::Internal, e.to_string());
+    let size = file.metadata().map_err(internal)?.len();
+    if offset > size {
+        return Err(ErrorReply::new(
+            ErrorCode::BadRequest,
+            "Offset past end of file",
+        ));
+    }
+    file.seek(SeekFrom::Start(offset)).map_err(internal)?;
+    Ok((file, size))
+}
+
+/// Downloads `name` from the node at `address` into its `.part` file,
+/// resuming after whatever an earlier attempt already wrote.
+pub fn download(
+    address: &str,
+    key: &str,
+    dir: &Path,
+    name: &str,
+    size: u64,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let part = part_path(dir, name);
+    let mut offset = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    if offset > size {
+        fs::remove_file(&part)?;
+        offset = 0;
+    }
+    if offset > 0 {
+        println!("Resuming {} at {} of {} bytes", name, offset, size);
+    }
+    let mut connection = Connection::open(address, key, timeout)?;
+    let request = Request::Get {
+        offset,
+        name: name.to_string(),
+    };
+    match connection.call(request)? {
+        Ok(Response::File { size: remote_size }) if remote_size == size => {}
+        Ok(Response::File { size: remote_size }) => {
+            return Err(format!("expected {} bytes, node has {}", size, remote_size).into());
+        }
+        Ok(response) => return Err(format!("Unexpected reply: {:?}", response).into()),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part)?;
+    let copied = io::copy(&mut connection.reader().take(size - offset), &mut file)?;
+    if copied < size - offset {
+        return Err(format!(
+            "connection closed after {} of {} bytes",
+            offset + copied,
+            size
+        )
+        .into());
+    }
+    Ok(())
+}
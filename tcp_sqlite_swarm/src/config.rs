@@ -0,0 +1,462 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml_edit::{DocumentMut, Item, Table, value};
+use uuid::Uuid;
+
+/// Config file looked for in the working directory.
+pub const CONFIG_FILE: &str = "swarm.toml";
+pub const DEFAULT_PROFILE: &str = "default";
+// The `key=value` file written before profiles, imported on first use.
+const LEGACY_CONFIG_FILE: &str = "config.txt";
+const DATABASE_FILE: &str = "master_node.db";
+const SHARE_DIR: &str = "shared";
+
+/// One `[profile]` table of the config file. `node_id`, `master` and
+/// `advertise` are also written by `join`, `leave` and elections.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Profile {
+    node_id: Option<String>,
+    master: Option<String>,
+    bind: Option<String>,
+    advertise: Option<String>,
+    admin: Option<String>,
+    admin_public: Option<bool>,
+    data_dir: Option<PathBuf>,
+    master_key: Option<String>,
+    timeouts: TimeoutSettings,
+}
+
+/// `[profile.timeouts]`, in seconds.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutSettings {
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+    fetch: Option<u64>,
+    query: Option<u64>,
+}
+
+/// Command line flags. They win over the environment, which wins over the
+/// config file.
+#[derive(Default)]
+pub struct Flags {
+    config: Option<String>,
+    profile: Option<String>,
+    bind: Option<String>,
+    advertise: Option<String>,
+    admin: Option<String>,
+    data_dir: Option<String>,
+}
+
+impl Flags {
+    /// Takes the flags out of `args`, as `--flag value` or `--flag=value`,
+    /// and leaves the command and its arguments.
+    pub fn parse(args: &[String]) -> Result<(Flags, Vec<String>), String> {
+        let mut flags = Flags::default();
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let slot = match name {
+                "--config" => &mut flags.config,
+                "--profile" => &mut flags.profile,
+                "--bind" => &mut flags.bind,
+                "--advertise" => &mut flags.advertise,
+                "--admin" => &mut flags.admin,
+                "--data-dir" => &mut flags.data_dir,
+                _ => {
+                    rest.push(arg.clone());
+                    continue;
+                }
+            };
+            let value = match inline {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs a value", name))?,
+            };
+            *slot = Some(value);
+        }
+        Ok((flags, rest))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub heartbeat_interval: Duration,
+    /// A node that missed this many seconds of beats is marked inactive, and
+    /// a master that missed them is replaced.
+    pub heartbeat_timeout: Duration,
+    pub fetch: Duration,
+    /// How long the master waits for the slowest node of a query.
+    pub query: Duration,
+}
+
+/// The settings of one profile, with the environment and flags applied.
+#[derive(Clone)]
+pub struct Config {
+    pub path: PathBuf,
+    pub profile: String,
+    /// This node's id, which outlives leaving the swarm.
+    pub node_id: String,
+    /// `None` unless this node is part of a swarm.
+    pub master: Option<String>,
+    pub bind: Option<SocketAddr>,
+    /// How the rest of the swarm reaches this node.
+    pub advertise: Option<SocketAddr>,
+    /// Where the master serves its HTTP admin API, if anywhere.
+    pub admin: Option<SocketAddr>,
+    pub data_dir: PathBuf,
+    /// The swarm secret. It never goes over the wire; peers prove they know
+    /// it by signing a fresh nonce from the server.
+    pub master_key: String,
+    pub timeouts: Timeouts,
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// The value of a setting that is a flag, an environment variable and a key
+/// of the config file, with where it came from.
+fn pick(
+    flag: &Option<String>,
+    flag_name: &str,
+    env_name: &str,
+    file: Option<String>,
+    origin: impl FnOnce() -> String,
+) -> Option<(String, String)> {
+    match (flag, env_var(env_name), file) {
+        (Some(value), _, _) => Some((value.clone(), flag_name.to_string())),
+        (None, Some(value), _) => Some((value, env_name.to_string())),
+        (None, None, Some(value)) => Some((value, origin())),
+        (None, None, None) => None,
+    }
+}
+
+fn parse_address((address, source): (String, String)) -> Result<(SocketAddr, String), String> {
+    match address.parse() {
+        Ok(parsed) => Ok((parsed, source)),
+        Err(_) => Err(format!(
+            "{}: `{}` is not an ip:port address",
+            source, address
+        )),
+    }
+}
+
+/// Whether `address` looks like `host:port`.
+fn is_address(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+impl Config {
+    /// Reads the selected profile and applies the environment and `flags` on
+    /// top. A missing file or profile is an empty one: a node that is not
+    /// part of a swarm yet.
+    pub fn load(flags: &Flags) -> Result<Config, Box<dyn Error>> {
+        let explicit_path = flags.config.clone().or_else(|| env_var("SWARM_CONFIG"));
+        let path = PathBuf::from(explicit_path.as_deref().unwrap_or(CONFIG_FILE));
+        let profile_name = flags
+            .profile
+            .clone()
+            .or_else(|| env_var("SWARM_PROFILE"))
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+        if explicit_path.is_none() && !path.exists() && Path::new(LEGACY_CONFIG_FILE).exists() {
+            import_legacy(&path, &profile_name)?;
+        }
+        let mut profiles: BTreeMap<String, Profile> = match fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| format!("Invalid {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Can't read {}: {}", path.display(), e).into()),
+        };
+        let profile = profiles.remove(&profile_name).unwrap_or_default();
+        let origin = |key: &str| format!("{} [{}] {}", path.display(), profile_name, key);
+
+        let node_id = match profile.node_id {
+            Some(node_id) if Uuid::parse_str(&node_id).is_err() => {
+                return Err(format!("{}: `{}` is not a UUID", origin("node_id"), node_id).into());
+            }
+            Some(node_id) => node_id,
+            None => Uuid::new_v4().to_string(),
+        };
+        if let Some(master) = profile
+            .master
+            .as_deref()
+            .filter(|master| !is_address(master))
+        {
+            return Err(format!(
+                "{}: `{}` is not a host:port address",
+                origin("master"),
+                master
+            )
+            .into());
+        }
+
+        let bind = pick(&flags.bind, "--bind", "SWARM_BIND", profile.bind, || {
+            origin("bind")
+        })
+        .map(parse_address)
+        .transpose()?;
+        let advertise = pick(
+            &flags.advertise,
+            "--advertise",
+            "SWARM_ADVERTISE",
+            profile.advertise,
+            || origin("advertise"),
+        )
+        .map(parse_address)
+        .transpose()?;
+        if let Some((advertise, source)) = &advertise
+            && advertise.ip().is_unspecified()
+        {
+            return Err(format!(
+                "{}: other nodes can't reach {}, use an address of this host",
+                source, advertise
+            )
+            .into());
+        }
+
+        let admin = pick(
+            &flags.admin,
+            "--admin",
+            "SWARM_ADMIN",
+            profile.admin,
+            || origin("admin"),
+        )
+        .map(parse_address)
+        .transpose()?;
+        if let Some((admin, source)) = &admin {
+            // The admin API has no authentication, so only a local user
+            // reaches it unless the profile says otherwise.
+            let public = match env_var("SWARM_ADMIN_PUBLIC") {
+                Some(value) => match value.as_str() {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => {
+                        return Err(format!(
+                            "SWARM_ADMIN_PUBLIC: `{}` is not true or false",
+                            value
+                        )
+                        .into());
+                    }
+                },
+                None => profile.admin_public.unwrap_or(false),
+            };
+            if !admin.ip().is_loopback() && !public {
+                return Err(format!(
+                    "{}: the admin API is unauthenticated, bind it to a loopback address \
+                     or set admin_public = true in [{}]",
+                    source, profile_name
+                )
+                .into());
+            }
+        }
+
+        // Relative paths in the file are relative to the file.
+        let base = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let data_dir = match flags.data_dir.clone().or_else(|| env_var("SWARM_DATA_DIR")) {
+            Some(data_dir) => PathBuf::from(data_dir),
+            None => profile
+                .data_dir
+                .map_or(base.clone(), |data_dir| base.join(data_dir)),
+        };
+        if data_dir.exists() && !data_dir.is_dir() {
+            return Err(format!("Data directory {} is not a directory", data_dir.display()).into());
+        }
+
+        let master_key = env_var("MASTER_KEY")
+            .or(profile.master_key.filter(|key| !key.is_empty()))
+            .ok_or_else(|| {
+                format!(
+                    "No master key: set MASTER_KEY or master_key in [{}] of {}",
+                    profile_name,
+                    path.display()
+                )
+            })?;
+
+        let seconds = |env_name: &str, file: Option<u64>, key: &str, default: u64| {
+            let (secs, source) = match env_var(env_name) {
+                Some(secs) => match secs.parse::<u64>() {
+                    Ok(parsed) => (parsed, env_name.to_string()),
+                    Err(_) => {
+                        return Err(format!(
+                            "{}: `{}` is not a number of seconds",
+                            env_name, secs
+                        ));
+                    }
+                },
+                None => (
+                    file.unwrap_or(default),
+                    origin(&format!("timeouts.{}", key)),
+                ),
+            };
+            if secs == 0 {
+                return Err(format!("{}: must be at least 1 second", source));
+            }
+            Ok(Duration::from_secs(secs))
+        };
+        let settings = profile.timeouts;
+        let timeouts = Timeouts {
+            heartbeat_interval: seconds(
+                "SWARM_HEARTBEAT_INTERVAL",
+                settings.heartbeat_interval,
+                "heartbeat_interval",
+                5,
+            )?,
+            heartbeat_timeout: seconds(
+                "SWARM_HEARTBEAT_TIMEOUT",
+                settings.heartbeat_timeout,
+                "heartbeat_timeout",
+                15,
+            )?,
+            fetch: seconds("SWARM_FETCH_TIMEOUT", settings.fetch, "fetch", 30)?,
+            query: seconds("SWARM_QUERY_TIMEOUT", settings.query, "query", 10)?,
+        };
+        if timeouts.heartbeat_timeout <= timeouts.heartbeat_interval {
+            return Err(format!(
+                "{}: heartbeat_timeout ({}s) must be longer than heartbeat_interval ({}s)",
+                origin("timeouts"),
+                timeouts.heartbeat_timeout.as_secs(),
+                timeouts.heartbeat_interval.as_secs()
+            )
+            .into());
+        }
+
+        Ok(Config {
+            path,
+            profile: profile_name,
+            node_id,
+            master: profile.master,
+            bind: bind.map(|(bind, _)| bind),
+            advertise: advertise.map(|(advertise, _)| advertise),
+            admin: admin.map(|(admin, _)| admin),
+            data_dir,
+            master_key,
+            timeouts,
+        })
+    }
+
+    /// The same node following the master at `address`.
+    pub fn with_master(&self, address: &str) -> Option<Self> {
+        is_address(address).then(|| Config {
+            master: Some(address.to_string()),
+            ..self.clone()
+        })
+    }
+
+    /// Where `serve` listens: `bind`, where a missing address or port is
+    /// taken from `advertise`.
+    pub fn bind_address(&self) -> SocketAddr {
+        let ip = match (self.bind, self.advertise) {
+            (Some(bind), _) => bind.ip(),
+            (None, Some(advertise)) => advertise.ip(),
+            (None, None) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        let port = match (self.bind, self.advertise) {
+            (Some(bind), _) if bind.port() != 0 => bind.port(),
+            (_, Some(advertise)) => advertise.port(),
+            _ => 0,
+        };
+        SocketAddr::new(ip, port)
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join(DATABASE_FILE)
+    }
+
+    /// Directory whose files a node shares with the swarm, and where fetched
+    /// files end up.
+    pub fn share_dir(&self) -> PathBuf {
+        self.data_dir.join(SHARE_DIR)
+    }
+
+    /// Writes this node's swarm state into its profile. Everything else in
+    /// the file, comments included, stays as it was.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut document = match fs::read_to_string(&self.path) {
+            Ok(content) => content.parse::<DocumentMut>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let profile = document
+            .entry(&self.profile)
+            .or_insert(Item::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| {
+                format!(
+                    "[{}] in {} is not a table",
+                    self.profile,
+                    self.path.display()
+                )
+            })?;
+        set(profile, "node_id", &self.node_id);
+        match &self.master {
+            Some(master) => set(profile, "master", master),
+            None => {
+                profile.remove("master");
+            }
+        }
+        if let Some(advertise) = self.advertise {
+            set(profile, "advertise", &advertise.to_string());
+        }
+        fs::write(&self.path, document.to_string())?;
+        Ok(())
+    }
+}
+
+// Replacing a value drops its comment, so unchanged ones are left alone.
+fn set(table: &mut Table, key: &str, new: &str) {
+    if table.get(key).and_then(Item::as_str) != Some(new) {
+        table[key] = value(new);
+    }
+}
+
+/// Moves the settings of a `config.txt` into `profile` of a new config file.
+fn import_legacy(path: &Path, profile: &str) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(LEGACY_CONFIG_FILE)?;
+    let values: BTreeMap<&str, &str> = content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+
+    let mut table = Table::new();
+    // Configs written before nodes had ids get one now.
+    let node_id = match values.get("node_id") {
+        Some(node_id) => node_id.to_string(),
+        None => Uuid::new_v4().to_string(),
+    };
+    table["node_id"] = value(node_id);
+    if let (Some(ip), Some(port)) = (values.get("master_ip_address"), values.get("master_port"))
+        && !ip.is_empty()
+    {
+        table["master"] = value(format!("{}:{}", ip, port));
+    }
+    if let (Some(ip), Some(port)) = (values.get("slave_ip_address"), values.get("slave_port")) {
+        table["advertise"] = value(format!("{}:{}", ip, port));
+    }
+
+    let mut document = DocumentMut::new();
+    document.insert(profile, Item::Table(table));
+    fs::write(path, document.to_string())?;
+    println!("Imported {} into {}", LEGACY_CONFIG_FILE, path.display());
+    Ok(())
+}
@@ -0,0 +1,367 @@
+use crate::admin::EventKind;
+use crate::protocol::{Request, Response};
+use crate::{Server, unix_time};
+use rusqlite::{Connection, OpenFlags, Result as DBResult};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// Who this node takes orders from.
+pub struct Leadership {
+    /// `None` while this node is the master.
+    pub master: Option<String>,
+    /// Raised by every election, so announcements from an older one lose.
+    pub term: u64,
+    pub electing: bool,
+    /// Last time the master answered a heartbeat.
+    pub last_contact: Instant,
+    /// The master this node replaced, told to follow as soon as it is back.
+    pub deposed: Option<String>,
+}
+
+impl Leadership {
+    pub fn new(master: Option<String>) -> Self {
+        Leadership {
+            master,
+            term: 0,
+            electing: false,
+            last_contact: Instant::now(),
+            deposed: None,
+        }
+    }
+}
+
+/// A row of the `servers` table as replicated to every node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Member {
+    pub node_id: String,
+    pub ip: String,
+    pub port: String,
+    pub is_active: bool,
+    pub has_left: bool,
+    pub joined_at: u64,
+    pub left_at: Option<u64>,
+    pub rejoined_at: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+impl Member {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// Members in order of seniority, which is also the order of precedence in
+/// an election.
+pub fn members(db: &Connection) -> DBResult<Vec<Member>> {
+    let mut stmt = db.prepare(
+        "SELECT node_id, ip_address, port, is_active, has_left,
+                joined_at, left_at, rejoined_at, last_seen
+         FROM servers ORDER BY joined_at, node_id",
+    )?;
+    stmt.query_map([], |row| {
+        Ok(Member {
+            node_id: row.get(0)?,
+            ip: row.get(1)?,
+            port: row.get(2)?,
+            is_active: row.get(3)?,
+            has_left: row.get(4)?,
+            joined_at: row.get(5)?,
+            left_at: row.get(6)?,
+            rejoined_at: row.get(7)?,
+            last_seen: row.get(8)?,
+        })
+    })?
+    .collect()
+}
+
+/// Addresses of the members in the local replica, for the CLI to try when
+/// the master it knows of is gone.
+pub fn known_members(database: &Path) -> Vec<String> {
+    let Ok(db) = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY) else {
+        return Vec::new();
+    };
+    members(&db)
+        .map(|members| {
+            members
+                .iter()
+                .filter(|member| !member.has_left)
+                .map(Member::address)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Replaces the local replica of the `servers` table.
+fn store_members(db: &mut Connection, members: &[Member]) -> DBResult<()> {
+    let tx = db.transaction()?;
+    tx.execute("DELETE FROM servers", [])?;
+    for member in members {
+        tx.execute(
+            "INSERT INTO servers (node_id, ip_address, port, is_active, has_left,
+                                  joined_at, left_at, rejoined_at, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            rusqlite::params![
+                member.node_id,
+                member.ip,
+                member.port,
+                member.is_active,
+                member.has_left,
+                member.joined_at,
+                member.left_at,
+                member.rejoined_at,
+                member.last_seen
+            ],
+        )?;
+    }
+    tx.commit()
+}
+
+impl Server {
+    pub fn master(&self) -> Option<String> {
+        self.leadership.lock().unwrap().master.clone()
+    }
+
+    /// Runs on slaves after every heartbeat, so any of them can take over
+    /// with an up to date view of the swarm.
+    pub fn replicate_members(&self, master: &str) -> Result<(), Box<dyn Error>> {
+        let reply = Self::ask_node(
+            master,
+            &self.master_key,
+            Request::Members,
+            self.config.timeouts.heartbeat_interval,
+        )?;
+        let members = match reply? {
+            Response::Members { members } => members,
+            response => return Err(format!("Unexpected reply: {:?}", response).into()),
+        };
+        store_members(&mut self.database.lock().unwrap(), &members)?;
+        Ok(())
+    }
+
+    /// Starts an election in the background unless one is running already.
+    pub fn start_election(self: &Arc<Self>) {
+        {
+            let mut leadership = self.leadership.lock().unwrap();
+            if leadership.electing || leadership.master.is_none() {
+                return;
+            }
+            leadership.electing = true;
+        }
+        let server = Arc::clone(self);
+        thread::spawn(move || server.elect());
+    }
+
+    /// Bully election: the most senior member that is still up becomes the
+    /// master. This node asks every member above it; if none answers, it
+    /// takes over and tells the others.
+    fn elect(&self) {
+        let term = {
+            let mut leadership = self.leadership.lock().unwrap();
+            leadership.term += 1;
+            leadership.term
+        };
+        let me = self.address.to_string();
+        println!("🗳️ Master unreachable, starting election for term {}", term);
+
+        let members = match members(&self.database.lock().unwrap()) {
+            Ok(members) => members,
+            Err(e) => {
+                eprintln!("Error reading members: {}", e);
+                return self.election_over();
+            }
+        };
+        let members: Vec<Member> = members
+            .into_iter()
+            .filter(|member| !member.has_left)
+            .collect();
+        let Some(rank) = members
+            .iter()
+            .position(|member| member.node_id == self.config.node_id)
+        else {
+            eprintln!("This node is not a member of the swarm, it can't stand for election.");
+            return self.election_over();
+        };
+
+        let mut outranked = false;
+        for member in members[..rank].iter().filter(|member| member.is_active) {
+            let request = Request::Election {
+                term,
+                candidate: me.clone(),
+            };
+            match Self::ask_node(
+                &member.address(),
+                &self.master_key,
+                request,
+                self.config.timeouts.heartbeat_interval,
+            ) {
+                Ok(Ok(Response::Alive)) => outranked = true,
+                Ok(Ok(Response::Master { term, address })) => {
+                    self.follow(term, &address);
+                    return self.election_over();
+                }
+                _ => {}
+            }
+        }
+
+        if outranked {
+            println!("A more senior node is up, waiting for it to take over.");
+        } else {
+            self.promote(term, &members[rank + 1..]);
+        }
+        self.election_over()
+    }
+
+    fn election_over(&self) {
+        let mut leadership = self.leadership.lock().unwrap();
+        leadership.electing = false;
+        // Give the winner a full timeout to announce itself.
+        leadership.last_contact = Instant::now();
+    }
+
+    fn promote(&self, term: u64, followers: &[Member]) {
+        let me = self.address.to_string();
+        {
+            let mut leadership = self.leadership.lock().unwrap();
+            leadership.deposed = leadership.master.take();
+            leadership.term = leadership.term.max(term);
+        }
+        println!("👑 Elected master for term {}", term);
+        self.events
+            .publish(EventKind::Elected, &self.config.node_id, &me);
+
+        // The master has no row of its own, and its followers get a full
+        // heartbeat timeout to notice the new master.
+        let result = (|| -> DBResult<()> {
+            let db = self.database.lock().unwrap();
+            db.execute(
+                "DELETE FROM servers WHERE node_id = ?1",
+                [&self.config.node_id],
+            )?;
+            db.execute(
+                "UPDATE servers SET last_seen = ?1 WHERE has_left = 0 AND is_active = 1",
+                [unix_time()],
+            )?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            eprintln!("Error taking over the servers table: {}", e);
+        }
+        self.save_master(&me);
+
+        for follower in followers.iter().filter(|member| member.is_active) {
+            let request = Request::Coordinator {
+                term,
+                master: me.clone(),
+            };
+            if let Err(e) = Self::ask_node(
+                &follower.address(),
+                &self.master_key,
+                request,
+                self.config.timeouts.heartbeat_interval,
+            ) {
+                eprintln!("Could not announce to {}: {}", follower.address(), e);
+            }
+        }
+    }
+
+    /// Runs on the master: once the master it replaced is back, makes it a
+    /// follower. Its first heartbeat then finds it unknown, so it joins.
+    pub fn reclaim_deposed(&self) {
+        let (term, Some(deposed)) = ({
+            let leadership = self.leadership.lock().unwrap();
+            (leadership.term, leadership.deposed.clone())
+        }) else {
+            return;
+        };
+        let request = Request::Coordinator {
+            term,
+            master: self.address.to_string(),
+        };
+        let Ok(reply) = Self::ask_node(
+            &deposed,
+            &self.master_key,
+            request,
+            self.config.timeouts.heartbeat_interval,
+        ) else {
+            return;
+        };
+        match reply {
+            Ok(Response::Ok) => println!("🧭 Former master {} now follows this node", deposed),
+            Ok(response) => eprintln!(
+                "Former master {} refused to follow: {:?}",
+                deposed, response
+            ),
+            Err(e) => eprintln!("Former master {} refused to follow: {}", deposed, e),
+        }
+        self.leadership.lock().unwrap().deposed = None;
+    }
+
+    /// Answers `Election` from a less senior node.
+    pub fn handle_election(self: &Arc<Self>, term: u64, candidate: &str) -> Response {
+        let known = {
+            let mut leadership = self.leadership.lock().unwrap();
+            leadership.term = leadership.term.max(term);
+            let term = leadership.term;
+            match &leadership.master {
+                None => Some((term, self.address.to_string())),
+                Some(master)
+                    if leadership.last_contact.elapsed()
+                        < self.config.timeouts.heartbeat_timeout =>
+                {
+                    Some((term, master.clone()))
+                }
+                Some(_) => None,
+            }
+        };
+        match known {
+            Some((term, address)) => Response::Master { term, address },
+            None => {
+                println!("🗳️ {} called an election, standing as well", candidate);
+                self.start_election();
+                Response::Alive
+            }
+        }
+    }
+
+    /// Answers `Coordinator` from the winner of an election.
+    pub fn handle_coordinator(&self, term: u64, master: &str) -> Response {
+        let current = self.leadership.lock().unwrap().term;
+        if term < current {
+            Response::Stale { term: current }
+        } else {
+            self.follow(term, master);
+            Response::Ok
+        }
+    }
+
+    /// Takes orders from `master` from now on.
+    pub fn follow(&self, term: u64, master: &str) {
+        let master = (master != self.address.to_string()).then(|| master.to_string());
+        let changed = {
+            let mut leadership = self.leadership.lock().unwrap();
+            leadership.term = leadership.term.max(term);
+            leadership.last_contact = Instant::now();
+            let changed = leadership.master != master;
+            leadership.master = master.clone();
+            changed
+        };
+        if changed {
+            let master = master.unwrap_or_else(|| self.address.to_string());
+            println!("🧭 Following master {} (term {})", master, term);
+            self.save_master(&master);
+        }
+    }
+
+    /// Points the config file at `master`, so a restart and the CLI find it.
+    fn save_master(&self, master: &str) {
+        match self.config.with_master(master).map(|config| config.save()) {
+            Some(Ok(())) => {}
+            Some(Err(e)) => eprintln!("Error updating the config file: {}", e),
+            None => eprintln!("Invalid master address: {}", master),
+        }
+    }
+}
@@ -1,337 +1,1038 @@
-use rusqlite::{Connection, Result as DBResult};
+mod admin;
+mod auth;
+mod catalogue;
+mod config;
+mod election;
+mod protocol;
+mod query;
+
+use admin::{EventKind, Events};
+use catalogue::{Scanner, SharedFile};
+use config::{Config, Flags};
+use election::Leadership;
+use protocol::{
+    ErrorCode, ErrorReply, FileEntry, JoinOutcome, Message, NodeEntry, Reply, Request, Response,
+};
+use rusqlite::{Connection, OptionalExtension, Result as DBResult};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::{env, thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, process, thread};
+use uuid::Uuid;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Replies a node may forward a command through before the CLI gives up.
+const MAX_REDIRECTS: usize = 3;
 
 struct Server {
     master_key: String,
     listener: TcpListener,
-    database: Arc<Mutex<Connection>>,
-}
-
-struct Config {
-    master_ip_address: String,
-    master_port: u32,
-    slave_port: u32,
+    database: Mutex<Connection>,
+    started_at: u64,
+    config: Config,
+    // How the rest of the swarm reaches this node.
+    address: SocketAddr,
+    leadership: Mutex<Leadership>,
+    admin: Option<TcpListener>,
+    events: Events,
 }
 
 impl Server {
-    fn connect() -> Result<Self, Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => Self::connect_slave(config),
-            None => Self::connect_master(),
+    fn connect(config: Config) -> Result<Self, Box<dyn Error>> {
+        match config.master {
+            Some(_) => Self::connect_node(config),
+            None => Self::connect_master(config),
         }
     }
 
-    fn connect_master() -> Result<Self, Box<dyn Error>> {
-        let listener = match TcpListener::bind("127.0.0.1:8777") {
-            Ok(listener) => listener,
-            Err(_) => TcpListener::bind("127.0.0.1:0").expect("Can't connect to any port."),
+    /// Starts a new swarm. Its config points at itself, which is how it is
+    /// recognised as the master on the next start.
+    fn connect_master(mut config: Config) -> Result<Self, Box<dyn Error>> {
+        let listener = match (config.bind, config.advertise) {
+            (None, None) => match TcpListener::bind("127.0.0.1:8777") {
+                Ok(listener) => listener,
+                Err(_) => TcpListener::bind("127.0.0.1:0").expect("Can't connect to any port."),
+            },
+            _ => Self::listen(&config)?,
         };
-        let database = Arc::new(Mutex::new(Self::build_db()?));
-        let master_key =
-            std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
         let info = listener.local_addr()?;
-        println!(
-            "👑 Master Listening at: http://{}:{}",
-            info.ip(),
-            info.port()
-        );
-        Ok(Server {
-            listener,
-            database,
-            master_key,
-        })
+        let advertise = match config.advertise {
+            Some(advertise) if advertise.port() != 0 => advertise,
+            Some(advertise) => SocketAddr::new(advertise.ip(), info.port()),
+            None if info.ip().is_unspecified() => {
+                return Err(format!(
+                    "Listening on {}, set an advertise address other nodes can reach",
+                    info
+                )
+                .into());
+            }
+            None => info,
+        };
+        config.advertise = Some(advertise);
+        config.master = Some(advertise.to_string());
+        config.save()?;
+        Self::start(listener, config)
     }
 
-    fn connect_slave(config: Config) -> Result<Self, Box<dyn Error>> {
-        let listener = match TcpListener::bind(format!("127.0.0.1:{}", config.slave_port)) {
-            Ok(listener) => listener,
-            Err(_) => TcpListener::bind("127.0.0.1:0").expect("Can't connect to any port."),
-        };
-        let database = Arc::new(Mutex::new(Self::build_db()?));
-        let master_key =
-            std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
+    fn connect_node(config: Config) -> Result<Self, Box<dyn Error>> {
+        // The swarm knows this node by the address it joined with, so it
+        // can't move to another port.
+        let listener = Self::listen(&config)?;
+        Self::start(listener, config)
+    }
+
+    fn listen(config: &Config) -> Result<TcpListener, Box<dyn Error>> {
+        let address = config.bind_address();
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        Ok(listener)
+    }
+
+    fn start(listener: TcpListener, config: Config) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&config.data_dir)?;
+        let database = Mutex::new(Self::build_db(&config.database_path())?);
         let info = listener.local_addr()?;
+        let address = config
+            .advertise
+            .filter(|address| address.port() != 0)
+            .ok_or_else(|| {
+                format!(
+                    "No advertise address in [{}] of {}, join the swarm again",
+                    config.profile,
+                    config.path.display()
+                )
+            })?;
+        // A node that won an election points its config at itself.
+        let master = config
+            .master
+            .clone()
+            .filter(|master| *master != address.to_string());
+        if master.is_none() {
+            println!(
+                "👑 Master Listening at: http://{}:{}",
+                info.ip(),
+                info.port()
+            );
+        } else {
+            println!(
+                "🧑‍🌾 Listening as slave at: http://{}:{}",
+                info.ip(),
+                info.port()
+            );
+        }
+        let admin = match config.admin {
+            Some(admin) => {
+                let listener = TcpListener::bind(admin)
+                    .map_err(|e| format!("Can't serve the admin API on {}: {}", admin, e))?;
+                println!("📊 Admin API at: http://{}", listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        println!("Node id: {}", config.node_id);
         println!(
-            "🧑‍🌾 Listening as slave at: http://{}:{}",
-            info.ip(),
-            info.port()
+            "Profile: {}, data directory: {}",
+            config.profile,
+            config.data_dir.display()
         );
         Ok(Server {
             listener,
             database,
-            master_key,
+            master_key: config.master_key.clone(),
+            started_at: unix_time(),
+            config,
+            address,
+            leadership: Mutex::new(Leadership::new(master)),
+            admin,
+            events: Events::default(),
         })
     }
 
-    fn join(ip_addr: &str) -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(_) => {
-                println!("You are already part of a swarm. Type --help for more.")
-            }
-            None => {
-                let mut stream = TcpStream::connect(ip_addr)?;
-                let socket = stream.local_addr()?;
-                let master_key =
-                    std::env::var("MASTER_KEY").expect("MASTER_KEY environment variable not set");
-
-                let command = format!("JOIN {}", master_key);
-                writeln!(stream, "{}", command)?;
-
-                let mut response = String::new();
-                let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                let response = response.trim();
-                println!("Server: {}", response);
-
-                if response.contains("joined") {
-                    let parts: Vec<&str> = ip_addr.split(":").collect();
-                    let master_ip = parts[0].to_string();
-                    let master_port = parts[1].to_string();
-                    Self::create_config(master_ip, master_port, socket.port().to_string())?;
-                }
+    fn join(mut config: Config, ip_addr: &str) -> Result<(), Box<dyn Error>> {
+        if config.master.is_some() {
+            println!("You are already part of a swarm. Type --help for more.");
+            return Ok(());
+        }
+        let address = Self::join_address(&config)?;
+        config.advertise = Some(address);
+        let request = Request::Join {
+            node_id: config.node_id.clone(),
+            address: address.to_string(),
+        };
+        let timeout = config.timeouts.heartbeat_interval;
+        match Self::ask_master(&config, ip_addr, request, timeout)? {
+            (master, Ok(Response::Joined { outcome })) => {
+                println!("Server: {}", outcome);
+                let config = config
+                    .with_master(&master)
+                    .ok_or_else(|| format!("Invalid master address: {}", master))?;
+                config.save()?;
+                println!(
+                    "Saved profile [{}] to {}",
+                    config.profile,
+                    config.path.display()
+                );
             }
+            (_, Ok(response)) => return Err(response.unexpected()),
+            (_, Err(e)) => eprintln!("Server: {}", e),
         }
         Ok(())
     }
 
-    fn leave() -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => {
-                let mut stream = TcpStream::connect(format!(
-                    "{}:{}",
-                    config.master_ip_address, config.master_port
-                ))?;
-                writeln!(stream, "LEAVE")?;
-                let mut response = String::new();
-                let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                let response = response.trim();
-                println!("Server: {}", response);
-
-                if response.contains("left") {
-                    fs::remove_file("config.txt")?;
-                    println!("Config file has been deleted!")
-                }
+    /// The address a joining node gives the master. Without an advertised
+    /// port, a free one is reserved for `serve` to listen on later.
+    fn join_address(config: &Config) -> Result<SocketAddr, Box<dyn Error>> {
+        if let Some(advertise) = config.advertise
+            && advertise.port() != 0
+        {
+            return Ok(advertise);
+        }
+        let ip = match (config.advertise, config.bind) {
+            (Some(advertise), _) => advertise.ip(),
+            (None, Some(bind)) if bind.ip().is_unspecified() => {
+                return Err(format!(
+                    "Binding to {}, set an advertise address other nodes can reach",
+                    bind
+                )
+                .into());
             }
-            None => {
-                eprintln!("You are not a part of any swarm! no action required.");
+            (None, Some(bind)) => bind.ip(),
+            (None, None) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        let port = TcpListener::bind(config.bind_address())?
+            .local_addr()?
+            .port();
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    fn leave(config: Config) -> Result<(), Box<dyn Error>> {
+        if config.master.is_none() {
+            eprintln!("You are not a part of any swarm! no action required.");
+            return Ok(());
+        }
+        let request = Request::Leave {
+            node_id: config.node_id.clone(),
+        };
+        match Self::ask(&config, request, config.timeouts.heartbeat_interval)? {
+            Ok(Response::Left) => {
+                println!("Server: Swam has been left!");
+                // Keep the node id, so joining again is a rejoin.
+                let config = Config {
+                    master: None,
+                    ..config
+                };
+                config.save()?;
+                println!(
+                    "Removed the master from profile [{}] in {}",
+                    config.profile,
+                    config.path.display()
+                );
             }
+            Ok(response) => return Err(response.unexpected()),
+            Err(e) => eprintln!("Server: {}", e),
         }
         Ok(())
     }
 
-    fn list() -> Result<(), Box<dyn Error>> {
-        match Self::verify_config() {
-            Some(config) => {
-                let mut stream = TcpStream::connect(format!(
-                    "{}:{}",
-                    config.master_ip_address, config.master_port
-                ))?;
-                writeln!(stream, "LIST")?;
-                let mut response = String::new();
-                let mut reader = BufReader::new(stream.try_clone()?);
-                reader.read_line(&mut response)?;
-                let response = response.trim();
-                println!("Server: {}", response);
-
-                if response.contains("left") {
-                    fs::remove_file("config.txt")?;
-                    println!("Config file has been deleted!")
+    fn list(config: Config) -> Result<(), Box<dyn Error>> {
+        if config.master.is_none() {
+            eprintln!(
+                "You can not fetch files from any server becasue you are not a part of a swarm."
+            );
+            return Ok(());
+        }
+        match Self::ask(&config, Request::List, config.timeouts.heartbeat_interval)? {
+            Ok(Response::Listing { nodes, files }) => {
+                println!("Active servers: {}", nodes.len());
+                for node in &nodes {
+                    println!("  {}  (node {})", node.address, node.node_id);
+                }
+                println!("Shared files: {}", files.len());
+                for file in &files {
+                    println!(
+                        "  {} ({} bytes, sha256 {}) on {}",
+                        file.name,
+                        file.size,
+                        &file.hash[..12.min(file.hash.len())],
+                        file.address
+                    );
                 }
             }
-            None => {
-                eprintln!(
-                    "You can not fetch files from any server becasue you are not a part of a swarm."
+            Ok(response) => return Err(response.unexpected()),
+            Err(e) => eprintln!("Server: {}", e),
+        }
+        Ok(())
+    }
+
+    fn status(config: Config, target: Option<&str>) -> Result<(), Box<dyn Error>> {
+        if config.master.is_none() {
+            eprintln!("You can not check the status because you are not a part of a swarm.");
+            return Ok(());
+        }
+        let request = Request::Status {
+            target: target.map(str::to_string),
+        };
+        match Self::ask(&config, request, config.timeouts.heartbeat_interval)? {
+            Ok(Response::Status {
+                now,
+                uptime,
+                version,
+                nodes,
+            }) => {
+                println!(
+                    "Master: up {}, version {}",
+                    format_duration(uptime),
+                    version
                 );
+                if let Some(target) = target
+                    && nodes.is_empty()
+                {
+                    println!("No node found for {}", target);
+                }
+                for node in &nodes {
+                    println!("{}", node.describe(now));
+                }
             }
+            Ok(response) => return Err(response.unexpected()),
+            Err(e) => eprintln!("Server: {}", e),
         }
         Ok(())
     }
 
-    fn create_config(
-        ip: String,
-        master_port: String,
-        slave_port: String,
-    ) -> Result<(), Box<dyn Error>> {
-        let filename = "config.txt";
-        if !Path::new(filename).exists() {
-            let data = format!(
-                "master_ip_address={}\nmaster_port={}\nslave_port={}",
-                ip, master_port, slave_port
+    fn fetch(config: Config, name: &str) -> Result<(), Box<dyn Error>> {
+        if config.master.is_none() {
+            eprintln!("You can not fetch files because you are not a part of a swarm.");
+            return Ok(());
+        }
+        if !catalogue::is_valid_name(name) {
+            eprintln!("Invalid file name: {}", name);
+            return Ok(());
+        }
+
+        let request = Request::Locate {
+            name: name.to_string(),
+        };
+        let sources = match Self::ask(&config, request, config.timeouts.heartbeat_interval)? {
+            Ok(Response::Sources { sources }) => sources,
+            Ok(response) => return Err(response.unexpected()),
+            Err(e) => {
+                eprintln!("Server: {}", e);
+                return Ok(());
+            }
+        };
+
+        let dir = config.share_dir();
+        let path = catalogue::shared_path(&dir, name);
+        if path.exists()
+            && sources
+                .iter()
+                .any(|source| catalogue::sha256_file(&path).is_ok_and(|local| local == source.hash))
+        {
+            println!("{} is already up to date.", path.display());
+            return Ok(());
+        }
+        fs::create_dir_all(&dir)?;
+
+        // Sources come fastest first. A broken transfer leaves the `.part`
+        // file behind, so the next source (or the next run) resumes it.
+        for source in &sources {
+            println!(
+                "Fetching {} ({} bytes) from {}",
+                name, source.size, source.address
             );
-            fs::write(filename, data)?;
-            println!("Config file has been created!");
-        } else {
-            println!("Config file already exist!");
+            if let Err(e) = catalogue::download(
+                &source.address,
+                &config.master_key,
+                &dir,
+                name,
+                source.size,
+                config.timeouts.fetch,
+            ) {
+                eprintln!("Download from {} failed: {}", source.address, e);
+                continue;
+            }
+
+            let part = catalogue::part_path(&dir, name);
+            if catalogue::sha256_file(&part)? != source.hash {
+                eprintln!(
+                    "Checksum mismatch for {} from {}, discarding it",
+                    name, source.address
+                );
+                fs::remove_file(&part)?;
+                continue;
+            }
+            fs::rename(&part, &path)?;
+            println!("Fetched {} into {}", name, path.display());
+            return Ok(());
         }
+        eprintln!("Could not fetch {} from any node.", name);
         Ok(())
     }
 
-    fn verify_config() -> Option<Config> {
-        let filename = "config.txt";
-        if Path::new(filename).exists() {
-            let content =
-                fs::read_to_string(filename).expect("Config file exist but no read permissions.");
-            let mut master_ip_address = String::new();
-            let mut master_port: u32 = 8777;
-            let mut slave_port: u32 = 8777;
-            for line in content.lines() {
-                if let Some((key, value)) = line.split_once('=') {
-                    match key {
-                        "master_ip_address" => master_ip_address = value.to_string(),
-                        "master_port" => {
-                            if let Ok(port) = value.parse::<u32>() {
-                                master_port = port;
-                            }
-                        }
-                        "slave_port" => {
-                            if let Ok(port) = value.parse::<u32>() {
-                                slave_port = port;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-            return Some(Config {
-                master_ip_address,
-                master_port,
-                slave_port,
-            });
+    fn query(config: Config, sql: &str) -> Result<(), Box<dyn Error>> {
+        if config.master.is_none() {
+            eprintln!("You can not query the swarm because you are not a part of a swarm.");
+            return Ok(());
+        }
+        // The master waits up to the query timeout for the slowest node.
+        let timeout = config.timeouts.query + config.timeouts.heartbeat_interval;
+        let request = Request::Query {
+            sql: sql.to_string(),
+        };
+        match Self::ask(&config, request, timeout)? {
+            Ok(Response::Rows {
+                columns,
+                rows,
+                failures,
+            }) => query::print_result(columns, rows, &failures),
+            Ok(response) => return Err(response.unexpected()),
+            Err(e) => eprintln!("Server: {}", e),
         }
-        return None;
+        Ok(())
     }
 
-    fn build_db() -> DBResult<Connection> {
-        let conn = Connection::open("master_node.db")?;
+    fn build_db(path: &Path) -> DBResult<Connection> {
+        let conn = Connection::open(path)?;
+
+        // Before node ids, members were keyed by the ephemeral port of their
+        // JOIN connection, which no node can be matched with. Those nodes
+        // join again on their next heartbeat.
+        let columns = {
+            let mut stmt = conn.prepare("PRAGMA table_info(servers)")?;
+            stmt.query_map([], |row| row.get::<_, String>(1))?
+                .collect::<DBResult<Vec<String>>>()?
+        };
+        if !columns.is_empty() && !columns.iter().any(|column| column == "node_id") {
+            conn.execute("DROP TABLE servers", [])?;
+            conn.execute("DROP TABLE IF EXISTS catalogue", [])?;
+        }
+
         conn.execute(
             "CREATE TABLE IF NOT EXISTS servers (
-                    id INTEGER PRIMARY KEY,
+                    node_id VARCHAR PRIMARY KEY,
                     ip_address VARCHAR NOT NULL,
                     port VARCHAR NOT NULL,
                     is_active BOOLEAN DEFAULT 1,
-                    has_left BOOLEAN DEFAULT 0
+                    has_left BOOLEAN DEFAULT 0,
+                    joined_at INTEGER NOT NULL,
+                    left_at INTEGER,
+                    rejoined_at INTEGER,
+                    last_seen INTEGER,
+                    latency_ms INTEGER,
+                    version VARCHAR,
+                    started_at INTEGER
+                )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS catalogue (
+                    ip_address VARCHAR NOT NULL,
+                    port VARCHAR NOT NULL,
+                    name VARCHAR NOT NULL,
+                    size INTEGER NOT NULL,
+                    hash VARCHAR NOT NULL,
+                    PRIMARY KEY (ip_address, port, name)
                 )",
             [],
         )?;
         Ok(conn)
     }
 
+    /// Sends a master request for the CLI. The master may have changed since
+    /// the config file was written, so the reply's source is remembered.
+    fn ask(config: &Config, request: Request, timeout: Duration) -> Result<Reply, Box<dyn Error>> {
+        let current = config.master.as_deref().unwrap_or_default();
+        let (master, reply) = Self::ask_master(config, current, request, timeout)?;
+        if master != current {
+            println!("Master has moved to {}", master);
+            if let Some(config) = config.with_master(&master) {
+                config.save()?;
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Sends `request` to `master`, following `Redirect` replies. If the
+    /// master is unreachable, any other member this node knows of points the
+    /// way to the new one. Returns the address that answered and its reply.
+    fn ask_master(
+        config: &Config,
+        master: &str,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<(String, Reply), Box<dyn Error>> {
+        let mut candidates = vec![master.to_string()];
+        candidates.extend(
+            election::known_members(&config.database_path())
+                .into_iter()
+                .filter(|member| member != master),
+        );
+
+        let mut last_error: Box<dyn Error> = "No node to ask".into();
+        for candidate in candidates {
+            let mut address = candidate;
+            for _ in 0..MAX_REDIRECTS {
+                match Self::ask_node(&address, &config.master_key, request.clone(), timeout) {
+                    Ok(Ok(Response::Redirect { master })) => address = master,
+                    Ok(reply) => return Ok((address, reply)),
+                    Err(e) => {
+                        last_error = format!("{}: {}", address, e).into();
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Sends `request` to a single node and waits for its reply.
+    fn ask_node(
+        address: &str,
+        master_key: &str,
+        request: Request,
+        timeout: Duration,
+    ) -> Result<Reply, Box<dyn Error>> {
+        protocol::Connection::open(address, master_key, timeout)?.call(request)
+    }
+
+    /// Runs on every node. The master checks heartbeats and publishes its
+    /// own files; slaves send heartbeats, replicate the membership and
+    /// advertise their files, and call an election once the master is gone.
+    fn maintain(self: Arc<Self>) {
+        let interval = self.config.timeouts.heartbeat_interval;
+        let mut latency_ms: u128 = 0;
+        let mut scanner = Scanner::default();
+        // Who the files were last published to: `None` for the own catalogue.
+        let mut advertised: Option<(Option<String>, Vec<SharedFile>)> = None;
+        loop {
+            let master = self.master();
+            let reachable = match &master {
+                None => {
+                    self.watch_heartbeats();
+                    self.reclaim_deposed();
+                    true
+                }
+                Some(master) => self.beat(master, &mut latency_ms),
+            };
+            if !reachable {
+                advertised = None;
+                thread::sleep(interval);
+                continue;
+            }
+
+            let share_dir = self.config.share_dir();
+            match scanner.scan(&share_dir) {
+                Ok(files) if advertised.as_ref() != Some(&(master.clone(), files.clone())) => {
+                    let result = match &master {
+                        Some(master) => self.advertise(master, &files),
+                        None => self.share_own_files(&files),
+                    };
+                    match result {
+                        Ok(()) => advertised = Some((master, files)),
+                        Err(e) => eprintln!("Publishing shared files failed: {}", e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Error scanning {}: {}", share_dir.display(), e),
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    /// Sends one heartbeat and acts on the answer. Returns whether `master`
+    /// took it.
+    fn beat(self: &Arc<Self>, master: &str, latency_ms: &mut u128) -> bool {
+        let sent = Instant::now();
+        match self.send_heartbeat(master, *latency_ms) {
+            Ok(Ok(Response::Ok)) => {
+                *latency_ms = sent.elapsed().as_millis();
+                self.leadership.lock().unwrap().last_contact = Instant::now();
+                if let Err(e) = self.replicate_members(master) {
+                    eprintln!("Replicating members from master failed: {}", e);
+                }
+                true
+            }
+            Ok(Ok(Response::Redirect { master })) => {
+                let term = self.leadership.lock().unwrap().term;
+                self.follow(term, &master);
+                false
+            }
+            Ok(Ok(response)) => {
+                eprintln!("Heartbeat rejected by master: {:?}", response);
+                false
+            }
+            Ok(Err(e)) if e.code == ErrorCode::UnknownNode => {
+                self.rejoin(master);
+                false
+            }
+            Ok(Err(e)) => {
+                eprintln!("Heartbeat rejected by master: {}", e);
+                false
+            }
+            Err(e) => {
+                eprintln!("Heartbeat to master {} failed: {}", master, e);
+                let last_contact = self.leadership.lock().unwrap().last_contact;
+                if last_contact.elapsed() >= self.config.timeouts.heartbeat_timeout {
+                    self.start_election();
+                }
+                false
+            }
+        }
+    }
+
+    /// Joins a master that has never heard of this node, such as the former
+    /// master this node took over from, under the same node id.
+    fn rejoin(&self, master: &str) {
+        let request = Request::Join {
+            node_id: self.config.node_id.clone(),
+            address: self.address.to_string(),
+        };
+        let timeout = self.config.timeouts.heartbeat_interval;
+        match Self::ask_node(master, &self.master_key, request, timeout) {
+            Ok(Ok(Response::Joined { outcome })) => println!("Master {}: {}", master, outcome),
+            Ok(Ok(response)) => eprintln!("Joining master {} failed: {:?}", master, response),
+            Ok(Err(e)) => eprintln!("Joining master {} failed: {}", master, e),
+            Err(e) => eprintln!("Joining master {} failed: {}", master, e),
+        }
+    }
+
+    /// Tells the master that this node is alive, along with its version,
+    /// start time and the round trip time of the previous beat.
+    fn send_heartbeat(&self, master: &str, latency_ms: u128) -> Result<Reply, Box<dyn Error>> {
+        let request = Request::Heartbeat {
+            node_id: self.config.node_id.clone(),
+            version: VERSION.to_string(),
+            started_at: self.started_at,
+            latency_ms: latency_ms as u64,
+        };
+        let timeout = self.config.timeouts.heartbeat_interval;
+        Self::ask_node(master, &self.master_key, request, timeout)
+    }
+
+    /// Sends the master the full list of files this node shares.
+    fn advertise(&self, master: &str, files: &[SharedFile]) -> Result<(), Box<dyn Error>> {
+        let request = Request::Catalogue {
+            node_id: self.config.node_id.clone(),
+            files: files.to_vec(),
+        };
+        let timeout = self.config.timeouts.heartbeat_interval;
+        match Self::ask_node(master, &self.master_key, request, timeout)? {
+            Ok(Response::Ok) => Ok(()),
+            Ok(response) => Err(response.unexpected()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The master shares its own files like any other node.
+    fn share_own_files(&self, files: &[SharedFile]) -> Result<(), Box<dyn Error>> {
+        let ip = self.address.ip().to_string();
+        let port = self.address.port().to_string();
+        Self::store_catalogue(&mut self.database.lock().unwrap(), &ip, &port, files)?;
+        Ok(())
+    }
+
+    /// Replaces the catalogue entries of one node.
+    fn store_catalogue(
+        db: &mut Connection,
+        ip: &str,
+        port: &str,
+        files: &[SharedFile],
+    ) -> DBResult<()> {
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM catalogue WHERE ip_address = ?1 AND port = ?2",
+            [ip, port],
+        )?;
+        for file in files {
+            tx.execute(
+                "INSERT INTO catalogue (ip_address, port, name, size, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![ip, port, file.name, file.size, file.hash],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Runs on the master: marks nodes that stopped sending heartbeats as
+    /// inactive.
+    fn watch_heartbeats(&self) {
+        let deadline = unix_time().saturating_sub(self.config.timeouts.heartbeat_timeout.as_secs());
+        let db = self.database.lock().unwrap();
+        let result = (|| -> DBResult<Vec<(String, String)>> {
+            let mut stmt = db.prepare(
+                "UPDATE servers SET is_active = 0
+                 WHERE has_left = 0 AND is_active = 1 AND IFNULL(last_seen, 0) < ?1
+                 RETURNING node_id, ip_address || ':' || port",
+            )?;
+            stmt.query_map([deadline], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })();
+        match result {
+            Ok(lost) => {
+                for (node_id, address) in lost {
+                    println!("💔 Node {} missed its heartbeats, marked inactive", address);
+                    self.events.publish(EventKind::Inactive, &node_id, &address);
+                }
+            }
+            Err(e) => eprintln!("Error checking heartbeats: {}", e),
+        }
+    }
+
     fn handle_connection(
+        self: &Arc<Self>,
         mut stream: TcpStream,
-        master_key: String,
         address: SocketAddr,
-        db: Arc<Mutex<Connection>>,
     ) -> Result<(), Box<dyn Error>> {
         let mut reader = BufReader::new(stream.try_clone()?);
-        let mut command = String::new();
-        reader.read_line(&mut command)?;
-        let command = command.trim();
-        println!("{}", command);
-
-        let commands: Vec<&str> = command.split(" ").collect();
-
-        match commands[0] {
-            "JOIN" => {
-                if master_key == commands[1].to_string() {
-                    let db = db.lock().unwrap();
-                    let mut stmt = db.prepare(
-                        "SELECT COUNT(*) FROM servers WHERE ip_address = ?1 AND port = ?2",
-                    )?;
-                    let exists: i64 = stmt.query_row(
-                        [address.ip().to_string(), address.port().to_string()],
-                        |row| row.get(0),
+        let Some(session) = protocol::accept(&mut reader, &mut stream, &self.master_key)? else {
+            eprintln!("Handshake with {} failed", address);
+            return Ok(());
+        };
+        // Ids only go up, so a signed request can't be replayed on the same
+        // connection.
+        let mut last_id = 0;
+        let mut max_len = protocol::MAX_UNVERIFIED_FRAME_LEN;
+        while let Some(message) = protocol::read_message_limited(&mut reader, max_len)? {
+            let Message::Request { id, auth, body } = message else {
+                let error = ErrorReply::new(ErrorCode::BadRequest, "Expected a request");
+                session.reply(&mut stream, 0, Err(error))?;
+                return Ok(());
+            };
+            if id <= last_id || !session.verify(id, &body, &auth) {
+                eprintln!("Authentication failed for {}", address);
+                let error =
+                    ErrorReply::new(ErrorCode::AuthenticationFailed, "Authentication failed!");
+                session.reply(&mut stream, id, Err(error))?;
+                return Ok(());
+            }
+            last_id = id;
+            max_len = protocol::MAX_FRAME_LEN;
+            println!("{}", body);
+
+            let master = if body.for_master() {
+                self.master()
+            } else {
+                None
+            };
+            let reply = match (master, body) {
+                (Some(master), _) => Ok(Response::Redirect { master }),
+                (None, Request::Get { offset, name }) => {
+                    // The file's bytes follow the reply, so nothing else can.
+                    let dir = self.config.share_dir();
+                    return catalogue::serve_file(&mut stream, &session, &dir, id, offset, &name);
+                }
+                (None, Request::Join { node_id, address }) => self.handle_join(&node_id, &address),
+                (None, Request::Leave { node_id }) => self.handle_leave(&node_id),
+                (None, Request::List) => self.handle_list(),
+                (None, Request::Status { target }) => self.handle_status(target.as_deref()),
+                (None, Request::Locate { name }) => self.handle_locate(&name),
+                (
+                    None,
+                    Request::Heartbeat {
+                        node_id,
+                        version,
+                        started_at,
+                        latency_ms,
+                    },
+                ) => self.handle_heartbeat(&node_id, &version, started_at, latency_ms),
+                (None, Request::Catalogue { node_id, files }) => {
+                    self.handle_catalogue(&node_id, &files)
+                }
+                (None, Request::Members) => election::members(&self.database.lock().unwrap())
+                    .map(|members| Response::Members { members })
+                    .map_err(ErrorReply::from),
+                (None, Request::Query { sql }) => self.fan_out(&sql),
+                (None, Request::Sql { sql }) => query::answer(
+                    &self.config.database_path(),
+                    &sql,
+                    self.config.timeouts.query,
+                ),
+                (None, Request::Election { term, candidate }) => {
+                    Ok(self.handle_election(term, &candidate))
+                }
+                (None, Request::Coordinator { term, master }) => {
+                    Ok(self.handle_coordinator(term, &master))
+                }
+            };
+            session.reply(&mut stream, id, reply)?;
+        }
+        Ok(())
+    }
+
+    /// Answers `Join`: adds a new node, takes back one that left, or updates
+    /// the address of a member.
+    fn handle_join(&self, node_id: &str, address: &str) -> Reply {
+        let (Ok(node_id), Ok(address)) = (Uuid::parse_str(node_id), address.parse::<SocketAddr>())
+        else {
+            return Err(ErrorReply::new(ErrorCode::BadRequest, "Invalid join!"));
+        };
+        let node_id = node_id.to_string();
+        let (ip, port) = (address.ip().to_string(), address.port().to_string());
+        let now = unix_time();
+
+        let db = self.database.lock().unwrap();
+        let has_left = db
+            .query_row(
+                "SELECT has_left FROM servers WHERE node_id = ?1",
+                [&node_id],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()?;
+        let outcome = match has_left {
+            None => {
+                db.execute(
+                    "INSERT INTO servers (node_id, ip_address, port, joined_at, last_seen)
+                     VALUES (?1, ?2, ?3, ?4, ?4)",
+                    rusqlite::params![node_id, ip, port, now],
+                )?;
+                JoinOutcome::Joined
+            }
+            Some(has_left) => {
+                // Files advertised under an old address are gone.
+                db.execute(
+                    "DELETE FROM catalogue WHERE (ip_address, port) IN (
+                        SELECT ip_address, port FROM servers
+                        WHERE node_id = ?1 AND NOT (ip_address = ?2 AND port = ?3))",
+                    [&node_id, &ip, &port],
+                )?;
+                if has_left {
+                    db.execute(
+                        "UPDATE servers
+                         SET ip_address = ?2, port = ?3, has_left = 0, is_active = 1,
+                             rejoined_at = ?4, last_seen = ?4
+                         WHERE node_id = ?1",
+                        rusqlite::params![node_id, ip, port, now],
                     )?;
-                    if exists > 0 {
-                        writeln!(stream, "Server already exists!")?;
-                    } else {
-                        db.execute(
-                            "INSERT INTO servers (ip_address, port) VALUES (?1, ?2)",
-                            [address.ip().to_string(), address.port().to_string()],
-                        )?;
-                        writeln!(stream, "Swam has been joined!")?;
-                    }
+                    JoinOutcome::Rejoined
                 } else {
-                    writeln!(stream, "Authentication failed!")?;
+                    db.execute(
+                        "UPDATE servers SET ip_address = ?2, port = ?3 WHERE node_id = ?1",
+                        [&node_id, &ip, &port],
+                    )?;
+                    JoinOutcome::Updated
                 }
             }
-            "LEAVE" => {
-                let db = db.lock().unwrap();
-                let mut stmt =
-                    db.prepare("SELECT has_left FROM servers WHERE ip_address = ?1 AND port= ?2")?;
-                let result = stmt.query_row(
-                    [address.ip().to_string(), address.port().to_string()],
-                    |row| row.get::<_, bool>(0),
-                );
-                match result {
-                    Ok(has_left) => {
-                        if has_left {
-                            writeln!(stream, "You have already left!")?;
-                        } else {
-                            db.execute(
-                                "UPDATE servers SET has_left = true WHERE ip_address = ?1 AND port= ?2",
-                                [address.ip().to_string(), address.port().to_string()],
-                            )?;
-                            writeln!(stream, "Swam has been left!")?;
-                        }
-                    }
-                    Err(_) => {
-                        db.execute(
-                            "INSERT INTO servers (ip_address, has_left) VALUES (?1, ?2)",
-                            rusqlite::params![address.ip().to_string(), false],
-                        )?;
-                        writeln!(stream, "Swam has been joined!")?;
-                    }
-                }
+        };
+        match outcome {
+            JoinOutcome::Joined => {
+                self.events
+                    .publish(EventKind::Joined, &node_id, &address.to_string())
             }
+            JoinOutcome::Rejoined => {
+                self.events
+                    .publish(EventKind::Rejoined, &node_id, &address.to_string())
+            }
+            JoinOutcome::Updated => {}
+        }
+        Ok(Response::Joined { outcome })
+    }
 
-            "LIST" => {
-                writeln!(stream, "Collecting from servers...")?;
-                let db = db.lock().unwrap();
-                let mut stmt =
-                    db.prepare("SELECT ip_address, port FROM servers WHERE has_left = 0")?;
-                let servers = stmt.query_map([], |row| {
-                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-                })?;
-                let mut active_servers: Vec<(String, String)> = Vec::new();
-                for server in servers {
-                    match server {
-                        Ok((ip, port)) => {
-                            active_servers.push((ip, port));
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading server: {}", e);
-                        }
-                    }
-                }
-                writeln!(stream, "Active servers: {}", active_servers.len())?;
-                for (ip, port) in &active_servers {
-                    writeln!(stream, "  {} : {}", ip, port)?;
-                }
+    fn handle_leave(&self, node_id: &str) -> Reply {
+        let db = self.database.lock().unwrap();
+        let node = db
+            .query_row(
+                "SELECT has_left, ip_address || ':' || port FROM servers WHERE node_id = ?1",
+                [node_id],
+                |row| Ok((row.get::<_, bool>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        match node {
+            Some((true, _)) => Err(ErrorReply::new(
+                ErrorCode::AlreadyLeft,
+                "You have already left!",
+            )),
+            Some((false, address)) => {
+                db.execute(
+                    "UPDATE servers SET has_left = 1, left_at = ?2 WHERE node_id = ?1",
+                    rusqlite::params![node_id, unix_time()],
+                )?;
+                self.events.publish(EventKind::Left, node_id, &address);
+                Ok(Response::Left)
             }
-            _ => {
-                writeln!(stream, "Unknown command!")?;
+            None => Err(ErrorReply::new(ErrorCode::UnknownNode, "Unknown node!")),
+        }
+    }
+
+    fn handle_list(&self) -> Reply {
+        let db = self.database.lock().unwrap();
+        let mut stmt = db.prepare(
+            "SELECT node_id, ip_address || ':' || port FROM servers
+             WHERE has_left = 0 ORDER BY joined_at, node_id",
+        )?;
+        let nodes = stmt
+            .query_map([], |row| {
+                Ok(NodeEntry {
+                    node_id: row.get(0)?,
+                    address: row.get(1)?,
+                })
+            })?
+            .collect::<DBResult<Vec<NodeEntry>>>()?;
+
+        let mut stmt = db.prepare(&format!(
+            "SELECT c.name, c.size, c.hash, c.ip_address || ':' || c.port {}
+             ORDER BY c.name, c.ip_address, c.port",
+            AVAILABLE_FILES
+        ))?;
+        let files = stmt
+            .query_map([], file_entry)?
+            .collect::<DBResult<Vec<FileEntry>>>()?;
+        Ok(Response::Listing { nodes, files })
+    }
+
+    fn handle_catalogue(&self, node_id: &str, files: &[SharedFile]) -> Reply {
+        if !files
+            .iter()
+            .all(|file| catalogue::is_valid_name(&file.name))
+        {
+            return Err(ErrorReply::new(ErrorCode::BadRequest, "Invalid catalogue!"));
+        }
+        let mut db = self.database.lock().unwrap();
+        let node = db
+            .query_row(
+                "SELECT ip_address, port FROM servers WHERE node_id = ?1 AND has_left = 0",
+                [node_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        let Some((ip, port)) = node else {
+            return Err(ErrorReply::new(ErrorCode::UnknownNode, "Unknown node!"));
+        };
+        Self::store_catalogue(&mut db, &ip, &port, files)?;
+        Ok(Response::Ok)
+    }
+
+    fn handle_locate(&self, name: &str) -> Reply {
+        let db = self.database.lock().unwrap();
+        let mut stmt = db.prepare(&format!(
+            "SELECT c.name, c.size, c.hash, c.ip_address || ':' || c.port {}
+             AND c.name = ?1 ORDER BY IFNULL(s.latency_ms, 0)",
+            AVAILABLE_FILES
+        ))?;
+        let sources = stmt
+            .query_map([name], file_entry)?
+            .collect::<DBResult<Vec<FileEntry>>>()?;
+        if sources.is_empty() {
+            return Err(ErrorReply::new(ErrorCode::NotFound, "File not found!"));
+        }
+        Ok(Response::Sources { sources })
+    }
+
+    fn handle_heartbeat(
+        &self,
+        node_id: &str,
+        version: &str,
+        started_at: u64,
+        latency_ms: u64,
+    ) -> Reply {
+        let db = self.database.lock().unwrap();
+        let node = db
+            .query_row(
+                "SELECT ip_address, port, is_active, has_left FROM servers
+                 WHERE node_id = ?1",
+                [node_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, bool>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        match node {
+            Some((_, _, _, true)) => Err(ErrorReply::new(
+                ErrorCode::LeftSwarm,
+                "You have left the swarm!",
+            )),
+            Some((ip, port, was_active, false)) => {
+                db.execute(
+                    "UPDATE servers
+                     SET is_active = 1, last_seen = ?2, latency_ms = ?3,
+                         version = ?4, started_at = ?5
+                     WHERE node_id = ?1",
+                    rusqlite::params![node_id, unix_time(), latency_ms, version, started_at],
+                )?;
+                if !was_active {
+                    println!("💚 Node {}:{} is back", ip, port);
+                    let address = format!("{}:{}", ip, port);
+                    self.events.publish(EventKind::Back, node_id, &address);
+                }
+                Ok(Response::Ok)
             }
+            None => Err(ErrorReply::new(ErrorCode::UnknownNode, "Unknown node!")),
         }
-        Ok(())
     }
 
-    fn run(&self) -> Result<(), Box<dyn Error>> {
+    fn handle_status(&self, target: Option<&str>) -> Reply {
+        let target = target.filter(|target| !target.is_empty());
+        let nodes = self.node_statuses()?;
+        // `status 10.0.0.5` matches every node on that host,
+        // `status 10.0.0.5:9000` or a node id a single one.
+        let nodes = nodes
+            .into_iter()
+            .filter(|node| {
+                target.is_none_or(|target| {
+                    target == node.ip
+                        || target == format!("{}:{}", node.ip, node.port)
+                        || target == node.node_id
+                })
+            })
+            .collect();
+
+        let now = unix_time();
+        Ok(Response::Status {
+            now,
+            uptime: now.saturating_sub(self.started_at),
+            version: VERSION.to_string(),
+            nodes,
+        })
+    }
+
+    /// Nodes that have not left, in order of seniority.
+    fn node_statuses(&self) -> DBResult<Vec<NodeStatus>> {
+        let db = self.database.lock().unwrap();
+        let mut stmt = db.prepare(
+            "SELECT node_id, ip_address, port, is_active, joined_at, rejoined_at,
+                    last_seen, latency_ms, version, started_at
+             FROM servers WHERE has_left = 0 ORDER BY joined_at, node_id",
+        )?;
+        stmt.query_map([], |row| {
+            Ok(NodeStatus {
+                node_id: row.get(0)?,
+                ip: row.get(1)?,
+                port: row.get(2)?,
+                is_active: row.get(3)?,
+                joined_at: row.get(4)?,
+                rejoined_at: row.get(5)?,
+                last_seen: row.get(6)?,
+                latency_ms: row.get(7)?,
+                version: row.get(8)?,
+                started_at: row.get(9)?,
+            })
+        })?
+        .collect()
+    }
+
+    fn run(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        let server = Arc::clone(&self);
+        thread::spawn(move || server.maintain());
+        if self.admin.is_some() {
+            let server = Arc::clone(&self);
+            thread::spawn(move || server.serve_admin());
+        }
+
         loop {
             match self.listener.accept() {
                 Ok((stream, address)) => {
-                    let master_key = self.master_key.clone();
-                    let database = self.database.clone();
+                    let server = Arc::clone(&self);
                     thread::spawn(move || {
-                        if let Err(e) =
-                            Self::handle_connection(stream, master_key, address, database)
-                        {
+                        if let Err(e) = server.handle_connection(stream, address) {
                             eprintln!("Error handling connection: {}", e);
                         };
                     });
@@ -344,27 +1045,144 @@ impl Server {
     }
 }
 
+/// Catalogue entries of nodes that are up: active slaves, plus the master's
+/// own files, which have no row in `servers`.
+const AVAILABLE_FILES: &str = "FROM catalogue c
+     LEFT JOIN servers s ON s.ip_address = c.ip_address AND s.port = c.port
+     WHERE (s.node_id IS NULL OR (s.is_active = 1 AND s.has_left = 0))";
+
+/// A catalogue entry selected as name, size, hash and address.
+fn file_entry(row: &rusqlite::Row) -> DBResult<FileEntry> {
+    Ok(FileEntry {
+        name: row.get(0)?,
+        size: row.get(1)?,
+        hash: row.get(2)?,
+        address: row.get(3)?,
+    })
+}
+
+/// A row of the `servers` table as reported by `status`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeStatus {
+    node_id: String,
+    ip: String,
+    port: String,
+    is_active: bool,
+    joined_at: u64,
+    rejoined_at: Option<u64>,
+    last_seen: Option<u64>,
+    latency_ms: Option<u64>,
+    version: Option<String>,
+    started_at: Option<u64>,
+}
+
+impl NodeStatus {
+    fn describe(&self, now: u64) -> String {
+        let last_seen = match self.last_seen {
+            Some(last_seen) => format!("{} ago", format_duration(now.saturating_sub(last_seen))),
+            None => "never".to_string(),
+        };
+        let uptime = match self.started_at {
+            Some(started_at) if self.is_active => format_duration(now.saturating_sub(started_at)),
+            _ => "-".to_string(),
+        };
+        let member_for = match self.rejoined_at {
+            Some(rejoined_at) => format!(
+                "rejoined {} ago",
+                format_duration(now.saturating_sub(rejoined_at))
+            ),
+            None => format!(
+                "joined {} ago",
+                format_duration(now.saturating_sub(self.joined_at))
+            ),
+        };
+        format!(
+            "  {}:{}  {}  up {}  version {}  last seen {}  latency {} ms  {}  node {}",
+            self.ip,
+            self.port,
+            if self.is_active { "active" } else { "inactive" },
+            uptime,
+            self.version.as_deref().unwrap_or("unknown"),
+            last_seen,
+            self.latency_ms.map_or("-".to_string(), |ms| ms.to_string()),
+            member_for,
+            self.node_id
+        )
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats seconds as e.g. `2d 3h 4m 5s`, leaving out leading zero units.
+fn format_duration(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+    ];
+    let mut formatted = String::new();
+    for (value, unit) in units {
+        if value > 0 || !formatted.is_empty() {
+            formatted.push_str(&format!("{}{} ", value, unit));
+        }
+    }
+    formatted.push_str(&format!("{}s", secs % 60));
+    formatted
+}
+
 // Main Functions
 fn main() -> Result<(), Box<dyn Error>> {
     dotenv::dotenv().ok();
-    let args: Vec<String> = env::args().collect();
-    match args.get(1).map(|s| s.as_str()) {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (flags, args) = match Flags::parse(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            print_usage();
+            return Ok(());
+        }
+    };
+    match args.first().map(|s| s.as_str()) {
         Some("join") => {
-            if args.len() != 3 {
+            if args.len() != 2 {
                 println!("Not enough arguments!");
                 print_usage();
             } else {
-                join_handler(&args[2])?;
+                join_handler(load_config(&flags), &args[1])?;
             }
         }
         Some("list") => {
-            list_handler()?;
+            list_handler(load_config(&flags))?;
         }
         Some("leave") => {
-            leave_handler()?;
+            leave_handler(load_config(&flags))?;
+        }
+        Some("fetch") => {
+            if args.len() != 2 {
+                println!("Not enough arguments!");
+                print_usage();
+            } else {
+                fetch_handler(load_config(&flags), &args[1])?;
+            }
+        }
+        Some("query") => {
+            if args.len() != 2 {
+                println!("Not enough arguments!");
+                print_usage();
+            } else {
+                query_handler(load_config(&flags), &args[1])?;
+            }
+        }
+        Some("status") => {
+            status_handler(load_config(&flags), args.get(1).map(|s| s.as_str()))?;
         }
         Some("serve") => {
-            run_server()?;
+            run_server(load_config(&flags))?;
         }
         Some("help") | Some("--help") => {
             print_usage();
@@ -374,24 +1192,46 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn run_server() -> Result<(), Box<dyn Error>> {
-    let server = Server::connect()?;
+fn load_config(flags: &Flags) -> Config {
+    Config::load(flags).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+fn run_server(config: Config) -> Result<(), Box<dyn Error>> {
+    let server = Arc::new(Server::connect(config)?);
     server.run()?;
     Ok(())
 }
 
-fn join_handler(ip_addr: &str) -> Result<(), Box<dyn Error>> {
-    Server::join(ip_addr)?;
+fn join_handler(config: Config, ip_addr: &str) -> Result<(), Box<dyn Error>> {
+    Server::join(config, ip_addr)?;
+    Ok(())
+}
+
+fn list_handler(config: Config) -> Result<(), Box<dyn Error>> {
+    Server::list(config)?;
+    Ok(())
+}
+
+fn leave_handler(config: Config) -> Result<(), Box<dyn Error>> {
+    Server::leave(config)?;
     Ok(())
 }
 
-fn list_handler() -> Result<(), Box<dyn Error>> {
-    Server::list()?;
+fn fetch_handler(config: Config, name: &str) -> Result<(), Box<dyn Error>> {
+    Server::fetch(config, name)?;
     Ok(())
 }
 
-fn leave_handler() -> Result<(), Box<dyn Error>> {
-    Server::leave()?;
+fn query_handler(config: Config, sql: &str) -> Result<(), Box<dyn Error>> {
+    Server::query(config, sql)?;
+    Ok(())
+}
+
+fn status_handler(config: Config, target: Option<&str>) -> Result<(), Box<dyn Error>> {
+    Server::status(config, target)?;
     Ok(())
 }
 
@@ -400,10 +1240,34 @@ fn print_usage() {
     println!("|| Usage:");
     println!("||=======================================================================");
     println!("||  * serve                                  - Start the server");
-    println!("||  * join <ip_address:port> <master_key>    - Join the running network");
+    println!("||  * join <ip_address:port>                 - Join the running network");
     println!("||  * leave                                  - Leave the network");
-    println!("||  * list                                   - List all active servers");
-    println!("||  * status <ip_address>                    - Check server status");
+    println!("||  * list                                   - List active servers and shared files");
+    println!("||  * fetch <file_name>                      - Download a file from the swarm");
+    println!("||  * status [ip_address[:port]|node_id]     - Check node health and uptime");
+    println!(
+        "||  * query \"<SELECT ...>\"                   - Run a read-only query on every node"
+    );
     println!("||  * help                                   - Show this message");
+    println!("||=======================================================================");
+    println!("|| Options, also read from SWARM_<OPTION> environment variables:");
+    println!("||=======================================================================");
+    println!("||  * --config <file>                        - Config file, swarm.toml by default");
+    println!(
+        "||  * --profile <name>                       - Profile in the config file, default by default"
+    );
+    println!("||  * --bind <ip:port>                       - Address to listen on");
+    println!(
+        "||  * --advertise <ip:port>                  - Address other nodes reach this one at"
+    );
+    println!(
+        "||  * --data-dir <dir>                       - Where the database and shared files live"
+    );
+    println!(
+        "||  * --admin <ip:port>                      - Serve the HTTP admin API on the master"
+    );
+    println!(
+        "||                                             on loopback unless SWARM_ADMIN_PUBLIC=true"
+    );
     println!("=========================================================================");
 }
@@ -0,0 +1,507 @@
+use crate::NodeStatus;
+use crate::auth;
+use crate::catalogue::SharedFile;
+use crate::election::Member;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Raised whenever a message changes shape. Nodes only talk to peers that
+/// speak the same version.
+pub const PROTOCOL_VERSION: u32 = 2;
+// Caps what a peer can make this node allocate for one frame.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+// Until a peer has signed a request it may only send this much, which still
+// fits a catalogue of a few thousand files.
+pub const MAX_UNVERIFIED_FRAME_LEN: usize = 1024 * 1024;
+const MAX_HELLO_LEN: usize = 1024;
+// How long a peer gets to accept the connection and finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a server waits for the next request before hanging up.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Everything that travels between nodes. Each message is one frame: its
+/// length as a big-endian `u32`, then that many bytes of JSON.
+///
+/// A connection opens with `Hello` and `Challenge`, each carrying a nonce.
+/// Then the client sends any number of requests, each signed with the
+/// challenge's nonce, and the server answers each with a `Reply` carrying
+/// the same id, signed with the hello's nonce. Only a refused handshake is
+/// answered unsigned.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello {
+        version: u32,
+        // Missing from older versions, which are turned away anyway.
+        #[serde(default)]
+        nonce: String,
+    },
+    Challenge {
+        version: u32,
+        nonce: String,
+    },
+    Request {
+        id: u64,
+        auth: String,
+        body: Request,
+    },
+    Reply {
+        id: u64,
+        #[serde(default)]
+        auth: String,
+        body: Result<Response, ErrorReply>,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Join {
+        node_id: String,
+        address: String,
+    },
+    Leave {
+        node_id: String,
+    },
+    List,
+    Status {
+        target: Option<String>,
+    },
+    Locate {
+        name: String,
+    },
+    Heartbeat {
+        node_id: String,
+        version: String,
+        started_at: u64,
+        latency_ms: u64,
+    },
+    /// Replaces everything the node shared before.
+    Catalogue {
+        node_id: String,
+        files: Vec<SharedFile>,
+    },
+    Members,
+    Query {
+        sql: String,
+    },
+    Get {
+        offset: u64,
+        name: String,
+    },
+    Sql {
+        sql: String,
+    },
+    Election {
+        term: u64,
+        candidate: String,
+    },
+    Coordinator {
+        term: u64,
+        master: String,
+    },
+}
+
+impl Request {
+    /// Requests only the master can answer; other nodes redirect them.
+    pub fn for_master(&self) -> bool {
+        matches!(
+            self,
+            Request::Join { .. }
+                | Request::Leave { .. }
+                | Request::List
+                | Request::Status { .. }
+                | Request::Locate { .. }
+                | Request::Heartbeat { .. }
+                | Request::Catalogue { .. }
+                | Request::Members
+                | Request::Query { .. }
+        )
+    }
+}
+
+/// One line per request for the server log.
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Join { node_id, address } => write!(f, "JOIN {} {}", node_id, address),
+            Request::Leave { node_id } => write!(f, "LEAVE {}", node_id),
+            Request::List => write!(f, "LIST"),
+            Request::Status { target } => {
+                write!(f, "STATUS {}", target.as_deref().unwrap_or_default())
+            }
+            Request::Locate { name } => write!(f, "LOCATE {}", name),
+            Request::Heartbeat {
+                node_id,
+                version,
+                started_at,
+                latency_ms,
+            } => write!(
+                f,
+                "HEARTBEAT {} {} {} {}",
+                node_id, version, started_at, latency_ms
+            ),
+            Request::Catalogue { node_id, files } => {
+                write!(f, "CATALOGUE {} {}", node_id, files.len())
+            }
+            Request::Members => write!(f, "MEMBERS"),
+            Request::Query { sql } => write!(f, "QUERY {}", sql),
+            Request::Get { offset, name } => write!(f, "GET {} {}", offset, name),
+            Request::Sql { sql } => write!(f, "SQL {}", sql),
+            Request::Election { term, candidate } => write!(f, "ELECTION {} {}", term, candidate),
+            Request::Coordinator { term, master } => write!(f, "COORDINATOR {} {}", term, master),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    /// This node is not the master; `master` is.
+    Redirect {
+        master: String,
+    },
+    Joined {
+        outcome: JoinOutcome,
+    },
+    Left,
+    Listing {
+        nodes: Vec<NodeEntry>,
+        files: Vec<FileEntry>,
+    },
+    Status {
+        now: u64,
+        uptime: u64,
+        version: String,
+        nodes: Vec<NodeStatus>,
+    },
+    /// Nodes that have a file, fastest first.
+    Sources {
+        sources: Vec<FileEntry>,
+    },
+    Members {
+        members: Vec<Member>,
+    },
+    /// Rows of a query, and the nodes it failed on.
+    Rows {
+        columns: Vec<String>,
+        rows: Vec<Vec<Option<String>>>,
+        failures: Vec<Failure>,
+    },
+    /// Followed on the connection by the file's bytes from the requested
+    /// offset on.
+    File {
+        size: u64,
+    },
+    /// The node stands in the election itself.
+    Alive,
+    Master {
+        term: u64,
+        address: String,
+    },
+    Stale {
+        term: u64,
+    },
+}
+
+impl Response {
+    /// For a caller that got a valid response, but not one that answers
+    /// its request.
+    pub fn unexpected(self) -> Box<dyn Error> {
+        format!("Unexpected reply: {:?}", self).into()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinOutcome {
+    Joined,
+    Rejoined,
+    /// The node was a member already; only its address was updated.
+    Updated,
+}
+
+impl fmt::Display for JoinOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinOutcome::Joined => write!(f, "Swam has been joined!"),
+            JoinOutcome::Rejoined => write!(f, "Swam has been rejoined!"),
+            JoinOutcome::Updated => write!(f, "Server already exists!"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub node_id: String,
+    pub address: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub address: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Failure {
+    pub node_id: String,
+    pub address: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    AuthenticationFailed,
+    BadRequest,
+    UnknownNode,
+    AlreadyLeft,
+    LeftSwarm,
+    NotFound,
+    QueryFailed,
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorReply {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ErrorReply {}
+
+impl From<rusqlite::Error> for ErrorReply {
+    fn from(e: rusqlite::Error) -> Self {
+        ErrorReply::new(ErrorCode::Internal, format!("Database error: {}", e))
+    }
+}
+
+/// What a node answered: a response, or the error it refused with.
+pub type Reply = Result<Response, ErrorReply>;
+
+pub fn write_message(writer: &mut impl Write, message: &Message) -> io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {} bytes is too large", payload.len()),
+        ));
+    }
+    // One write per frame, so Nagle's algorithm doesn't hold back the payload.
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next message, or `None` if the peer closed the connection
+/// between two messages.
+pub fn read_message(reader: &mut impl Read) -> io::Result<Option<Message>> {
+    read_message_limited(reader, MAX_FRAME_LEN)
+}
+
+/// Like `read_message`, refusing frames longer than `max_len`. The payload
+/// buffer grows as bytes arrive, not to the length the peer announced.
+pub fn read_message_limited(reader: &mut impl Read, max_len: usize) -> io::Result<Option<Message>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+fn write_reply(stream: &mut impl Write, id: u64, auth: String, body: Reply) -> io::Result<()> {
+    write_message(stream, &Message::Reply { id, auth, body })
+}
+
+/// Server side of an accepted connection.
+pub struct Session {
+    key: String,
+    // Requests are signed with this node's nonce, replies with the peer's.
+    nonce: String,
+    peer_nonce: String,
+}
+
+impl Session {
+    pub fn verify(&self, id: u64, request: &Request, signature: &str) -> bool {
+        auth::verify(&self.key, &self.nonce, id, request, signature)
+    }
+
+    /// Answers request `id` with a signed reply.
+    pub fn reply(&self, stream: &mut impl Write, id: u64, body: Reply) -> io::Result<()> {
+        let auth = auth::sign(&self.key, &self.peer_nonce, id, &body)?;
+        write_reply(stream, id, auth, body)
+    }
+}
+
+/// Server side of the handshake: checks the peer's protocol version and
+/// hands out the nonce its requests are signed with. Returns `None` if the
+/// peer was turned away; it has already been told why.
+pub fn accept(
+    reader: &mut impl Read,
+    stream: &mut TcpStream,
+    key: &str,
+) -> io::Result<Option<Session>> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let hello = read_message_limited(reader, MAX_HELLO_LEN)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let peer_nonce = match hello {
+        Some(Message::Hello { version, nonce })
+            if version == PROTOCOL_VERSION && !nonce.is_empty() =>
+        {
+            nonce
+        }
+        hello => {
+            let refusal = match hello {
+                Some(Message::Hello { version, .. }) if version != PROTOCOL_VERSION => {
+                    ErrorReply::new(
+                        ErrorCode::UnsupportedVersion,
+                        format!(
+                            "This node speaks protocol version {}, not {}",
+                            PROTOCOL_VERSION, version
+                        ),
+                    )
+                }
+                _ => ErrorReply::new(ErrorCode::BadRequest, "Expected a hello message"),
+            };
+            write_reply(stream, 0, String::new(), Err(refusal))?;
+            return Ok(None);
+        }
+    };
+
+    let nonce = auth::nonce()?;
+    write_message(
+        stream,
+        &Message::Challenge {
+            version: PROTOCOL_VERSION,
+            nonce: nonce.clone(),
+        },
+    )?;
+    Ok(Some(Session {
+        key: key.to_string(),
+        nonce,
+        peer_nonce,
+    }))
+}
+
+/// Client side of a connection to one node.
+pub struct Connection {
+    address: String,
+    key: String,
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+    // Requests are signed with the node's nonce, its replies with ours.
+    nonce: String,
+    own_nonce: String,
+    next_id: u64,
+}
+
+impl Connection {
+    /// Connects to `address` and shakes hands. Replies then get `timeout`
+    /// to arrive.
+    pub fn open(address: &str, key: &str, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        let socket = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("Can't resolve {}", address))?;
+        let mut stream = TcpStream::connect_timeout(&socket, HANDSHAKE_TIMEOUT)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let own_nonce = auth::nonce()?;
+        write_message(
+            &mut stream,
+            &Message::Hello {
+                version: PROTOCOL_VERSION,
+                nonce: own_nonce.clone(),
+            },
+        )?;
+        let nonce = match read_message(&mut reader)? {
+            Some(Message::Challenge { nonce, .. }) => nonce,
+            Some(Message::Reply { body: Err(e), .. }) => return Err(e.into()),
+            _ => return Err(format!("Unexpected handshake from {}", address).into()),
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(Connection {
+            address: address.to_string(),
+            key: key.to_string(),
+            reader,
+            stream,
+            nonce,
+            own_nonce,
+            next_id: 1,
+        })
+    }
+
+    /// Sends a signed `request` and waits for its reply, which must be
+    /// signed as well.
+    pub fn call(&mut self, request: Request) -> Result<Reply, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let auth = auth::sign(&self.key, &self.nonce, id, &request)?;
+        write_message(
+            &mut self.stream,
+            &Message::Request {
+                id,
+                auth,
+                body: request,
+            },
+        )?;
+        match read_message(&mut self.reader)? {
+            Some(Message::Reply {
+                id: replied,
+                auth,
+                body,
+            }) if replied == id => {
+                if !auth::verify(&self.key, &self.own_nonce, id, &body, &auth) {
+                    return Err(format!("Reply from {} failed authentication", self.address).into());
+                }
+                Ok(body)
+            }
+            Some(_) => Err(format!("Unexpected reply from {}", self.address).into()),
+            None => Err(format!("{} closed the connection", self.address).into()),
+        }
+    }
+
+    /// Data that follows a reply, such as the bytes of a file.
+    pub fn reader(&mut self) -> &mut BufReader<TcpStream> {
+        &mut self.reader
+    }
+}
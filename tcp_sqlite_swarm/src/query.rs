@@ -0,0 +1,257 @@
+use crate::Server;
+use crate::protocol::{self, ErrorCode, ErrorReply, Failure, Reply, Request, Response};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Rows one node may return, so a runaway query can't outgrow a frame. The
+// merged answer of the master is held to the same count.
+const MAX_ROWS: usize = 10_000;
+// Serialised bytes of the merged rows, leaving the rest of a frame for the
+// columns, the failures and the envelope.
+const MAX_MERGED_LEN: usize = protocol::MAX_FRAME_LEN / 2;
+// Named so it can't clash with a `node_id` the query selects itself.
+const NODE_COLUMN: &str = "swarm_node_id";
+
+/// Rows of one query, every value rendered as text.
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+/// Runs `sql` against this node's database. The connection is read-only and
+/// only statements that return rows are accepted. A query still running
+/// after `timeout` is interrupted.
+pub fn run_local(
+    database: &Path,
+    sql: &str,
+    timeout: Duration,
+) -> Result<ResultSet, Box<dyn Error>> {
+    let db = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let interrupt = db.get_interrupt_handle();
+    // Dropping `_done` on the way out tells the watchdog to stand down.
+    let (_done, finished) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+            interrupt.interrupt();
+        }
+    });
+    read_rows(&db, sql).map_err(|e| match e.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(error, _))
+            if error.code == rusqlite::ErrorCode::OperationInterrupted =>
+        {
+            format!("Timed out after {}s", timeout.as_secs()).into()
+        }
+        _ => e,
+    })
+}
+
+fn read_rows(db: &Connection, sql: &str) -> Result<ResultSet, Box<dyn Error>> {
+    let mut stmt = db.prepare(sql)?;
+    if !stmt.readonly() || stmt.column_count() == 0 {
+        return Err("Only read-only queries that return rows are allowed".into());
+    }
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = Vec::new();
+    let mut result = stmt.query([])?;
+    while let Some(row) = result.next()? {
+        if rows.len() == MAX_ROWS {
+            return Err(format!("More than {} rows, add a LIMIT", MAX_ROWS).into());
+        }
+        let mut values = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            values.push(match row.get_ref(i)? {
+                ValueRef::Null => None,
+                ValueRef::Integer(value) => Some(value.to_string()),
+                ValueRef::Real(value) => Some(value.to_string()),
+                ValueRef::Text(text) => Some(String::from_utf8_lossy(text).into_owned()),
+                ValueRef::Blob(blob) => Some(format!(
+                    "x'{}'",
+                    blob.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                )),
+            });
+        }
+        rows.push(values);
+    }
+    Ok(ResultSet { columns, rows })
+}
+
+/// Answers `Sql` with the rows of this node alone.
+pub fn answer(database: &Path, sql: &str, timeout: Duration) -> Reply {
+    match run_local(database, sql, timeout) {
+        Ok(result) => Ok(Response::Rows {
+            columns: result.columns,
+            rows: result.rows,
+            failures: Vec::new(),
+        }),
+        Err(e) => Err(ErrorReply::new(ErrorCode::QueryFailed, e.to_string())),
+    }
+}
+
+impl Server {
+    /// Answers `Query` on the master: runs it on every active node at once,
+    /// itself included, and merges the rows under a `swarm_node_id` column. Nodes
+    /// that fail or miss the query timeout are listed as failures, as are nodes
+    /// whose rows did not all fit in the merged result.
+    pub fn fan_out(&self, sql: &str) -> Reply {
+        let nodes = {
+            let db = self.database.lock().unwrap();
+            let mut stmt = db.prepare(
+                "SELECT node_id, ip_address || ':' || port FROM servers
+                 WHERE has_left = 0 AND is_active = 1 ORDER BY joined_at, node_id",
+            )?;
+            stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<(String, String)>>>()?
+        };
+
+        let timeout = self.config.timeouts.query;
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = mpsc::channel();
+        for (node_id, address) in nodes.iter().cloned() {
+            let sender = sender.clone();
+            let master_key = self.master_key.clone();
+            let request = Request::Sql {
+                sql: sql.to_string(),
+            };
+            thread::spawn(move || {
+                let result = match Self::ask_node(&address, &master_key, request, timeout) {
+                    Ok(Ok(Response::Rows { columns, rows, .. })) => Ok(ResultSet { columns, rows }),
+                    Ok(Ok(response)) => Err(response.unexpected().to_string()),
+                    Ok(Err(e)) => Err(e.message),
+                    Err(e) => Err(e.to_string()),
+                };
+                sender.send((node_id, result)).ok();
+            });
+        }
+        let node_id = self.config.node_id.clone();
+        let database = self.config.database_path();
+        let own_sql = sql.to_string();
+        thread::spawn(move || {
+            let result = run_local(&database, &own_sql, timeout).map_err(|e| e.to_string());
+            sender.send((node_id, result)).ok();
+        });
+
+        let mut results = Vec::new();
+        while results.len() <= nodes.len() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(remaining) {
+                Ok(result) => results.push(result),
+                Err(_) => break,
+            }
+        }
+
+        let mut columns: Option<Vec<String>> = None;
+        let mut failures = Vec::new();
+        let mut rows = Vec::new();
+        let mut merged_len = 0;
+        for (node_id, result) in results.iter() {
+            match result {
+                Ok(result) if columns.as_ref().is_some_and(|c| *c != result.columns) => {
+                    failures.push((node_id.clone(), "Returned different columns".to_string()))
+                }
+                Ok(result) => {
+                    columns.get_or_insert_with(|| result.columns.clone());
+                    for row in &result.rows {
+                        let mut merged = vec![Some(node_id.clone())];
+                        merged.extend(row.iter().cloned());
+                        // The row and the comma before it.
+                        let len =
+                            serde_json::to_vec(&merged).map_or(usize::MAX, |row| row.len() + 1);
+                        if rows.len() == MAX_ROWS || len > MAX_MERGED_LEN - merged_len {
+                            failures.push((
+                                node_id.clone(),
+                                format!(
+                                    "Rows left out, the merged result is limited to {} rows \
+                                     and {} MB, add a LIMIT",
+                                    MAX_ROWS,
+                                    MAX_MERGED_LEN / (1024 * 1024)
+                                ),
+                            ));
+                            break;
+                        }
+                        merged_len += len;
+                        rows.push(merged);
+                    }
+                }
+                Err(reason) => failures.push((node_id.clone(), reason.clone())),
+            }
+        }
+        let own = (self.config.node_id.clone(), String::new());
+        for (node_id, _) in nodes.iter().chain([&own]) {
+            if !results.iter().any(|(answered, _)| answered == node_id) {
+                failures.push((node_id.clone(), "Timed out".to_string()));
+            }
+        }
+
+        let columns = match columns {
+            Some(columns) => [NODE_COLUMN.to_string()]
+                .into_iter()
+                .chain(columns)
+                .collect(),
+            None => Vec::new(),
+        };
+        let failures = failures
+            .into_iter()
+            .map(|(node_id, reason)| {
+                let address = match nodes.iter().find(|(id, _)| *id == node_id) {
+                    Some((_, address)) => address.clone(),
+                    None => self.address.to_string(),
+                };
+                Failure {
+                    node_id,
+                    address,
+                    reason,
+                }
+            })
+            .collect();
+        Ok(Response::Rows {
+            columns,
+            rows,
+            failures,
+        })
+    }
+}
+
+/// Prints the master's answer to `Query` as a table, followed by the nodes
+/// whose rows are missing.
+pub fn print_result(columns: Vec<String>, rows: Vec<Vec<Option<String>>>, failures: &[Failure]) {
+    if !columns.is_empty() {
+        let mut table = vec![columns];
+        table.extend(rows.into_iter().map(|row| {
+            row.into_iter()
+                .map(|value| value.unwrap_or_else(|| "NULL".to_string()))
+                .collect()
+        }));
+        let mut widths = vec![0; table[0].len()];
+        for row in &table {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+        for (i, row) in table.iter().enumerate() {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(value, width)| format!("{:width$}", value, width = width))
+                .collect();
+            println!("{}", cells.join(" | ").trim_end());
+            if i == 0 {
+                let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+                println!("{}", rule.join("-+-"));
+            }
+        }
+        println!("({} rows)", table.len() - 1);
+    }
+    for failure in failures {
+        eprintln!(
+            "⚠️ Incomplete result from node {} ({}): {}",
+            failure.node_id, failure.address, failure.reason
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Runs a three node swarm on loopback, stops its master and checks that
+//! the other two elect a new one that `list` reaches.
+
+use rusqlite::{Connection, OpenFlags};
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MASTER_KEY: &str = "election-test";
+
+/// A node's config and data directory, and its `serve` process once started.
+struct Node {
+    dir: PathBuf,
+    advertise: String,
+    server: Option<Child>,
+}
+
+impl Node {
+    fn new(root: &Path, name: &str) -> Self {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        Node {
+            dir,
+            advertise: format!("127.0.0.1:{}", free_port()),
+            server: None,
+        }
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_tcp_sqlite_swarm"));
+        command
+            .arg("--config")
+            .arg(self.dir.join("swarm.toml"))
+            .arg("--data-dir")
+            .arg(&self.dir)
+            .args(["--advertise", &self.advertise])
+            .args(args)
+            .env_remove("SWARM_PROFILE")
+            .env("MASTER_KEY", MASTER_KEY)
+            .env("SWARM_HEARTBEAT_INTERVAL", "1")
+            .env("SWARM_HEARTBEAT_TIMEOUT", "3");
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    fn serve(&mut self) {
+        // Kept next to the data for when the test fails.
+        let log = fs::File::create(self.dir.join("serve.log")).unwrap();
+        let server = self
+            .command(&["serve"])
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        self.server = Some(server);
+        wait_for(&format!("{} to listen", self.advertise), || {
+            TcpStream::connect(&self.advertise).is_ok()
+        });
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+    }
+
+    /// The `list` output, if the command got an answer.
+    fn list(&self) -> Option<String> {
+        let output = self.run(&["list"]);
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        (output.status.success() && stdout.contains("Active servers")).then_some(stdout)
+    }
+
+    /// The members this node's replica of the `servers` table knows of.
+    fn members(&self) -> Vec<String> {
+        let path = self.dir.join("master_node.db");
+        let Ok(db) = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY) else {
+            return Vec::new();
+        };
+        db.prepare("SELECT ip_address || ':' || port FROM servers WHERE has_left = 0")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()
+            })
+            .unwrap_or_default()
+    }
+
+    fn master(&self) -> Option<String> {
+        let config = fs::read_to_string(self.dir.join("swarm.toml")).ok()?;
+        config.lines().find_map(|line| {
+            let value = line
+                .trim()
+                .strip_prefix("master")?
+                .trim()
+                .strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_string())
+        })
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !done() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+#[test]
+fn followers_elect_a_new_master() {
+    let root =
+        std::env::temp_dir().join(format!("tcp_sqlite_swarm-election-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let mut a = Node::new(&root, "a");
+    let mut b = Node::new(&root, "b");
+    let mut c = Node::new(&root, "c");
+
+    a.serve();
+    for node in [&mut b, &mut c] {
+        let output = node.run(&["join", &a.advertise]);
+        assert!(output.status.success(), "join failed: {:?}", output);
+        node.serve();
+    }
+    // A follower only stands for election once it has replicated the
+    // membership from the master.
+    let followers = [b.advertise.clone(), c.advertise.clone()];
+    wait_for("the followers to replicate the membership", || {
+        [&b, &c].iter().all(|node| {
+            let members = node.members();
+            followers.iter().all(|follower| members.contains(follower))
+        })
+    });
+
+    a.kill();
+    wait_for("a new master", || {
+        b.master().is_some_and(|master| followers.contains(&master)) && c.master() == b.master()
+    });
+    let master = b.master().unwrap();
+    let follower = followers
+        .iter()
+        .find(|&address| *address != master)
+        .unwrap();
+    // The follower shows up once its next heartbeat reaches the new master.
+    wait_for("list to reach the new master", || {
+        b.list().is_some_and(|listing| listing.contains(follower))
+    });
+
+    drop((a, b, c));
+    let _ = fs::remove_dir_all(&root);
+}